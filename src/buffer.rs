@@ -0,0 +1,20 @@
+use crate::{Document, Position};
+
+// A document along with the view state it had when it was last displayed
+pub struct Buffer {
+    pub id: usize,
+    pub document: Document,
+    pub cursor_position: Position,
+    pub offset: Position,
+}
+
+impl Buffer {
+    pub fn new(id: usize, document: Document) -> Self {
+        Self {
+            id,
+            document,
+            cursor_position: Position::default(),
+            offset: Position::default(),
+        }
+    }
+}
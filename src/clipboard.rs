@@ -0,0 +1,226 @@
+use crate::Terminal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Which provider reaches the system clipboard, chosen in the settings
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipboardProvider {
+    // wl-copy on Wayland, xclip on X11, OSC 52 otherwise (over SSH for instance)
+    #[default]
+    Auto,
+    Osc52,
+    WlCopy,
+    Xclip,
+    // Keeps everything inside the editor
+    Fake,
+}
+
+// "+ is the clipboard and "* the primary selection
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClipboardSelection {
+    Clipboard,
+    Primary,
+}
+
+pub trait Clipboard {
+    fn copy(&mut self, selection: ClipboardSelection, text: &str) -> io::Result<()>;
+    // None when the clipboard can't be read
+    fn paste(&mut self, selection: ClipboardSelection) -> io::Result<Option<String>>;
+}
+
+pub fn new(provider: ClipboardProvider) -> Box<dyn Clipboard> {
+    match provider {
+        ClipboardProvider::Auto => {
+            if env::var_os("WAYLAND_DISPLAY").is_some() && on_path("wl-copy") {
+                new(ClipboardProvider::WlCopy)
+            } else if env::var_os("DISPLAY").is_some() && on_path("xclip") {
+                new(ClipboardProvider::Xclip)
+            } else {
+                new(ClipboardProvider::Osc52)
+            }
+        }
+        ClipboardProvider::Osc52 => Box::new(Osc52),
+        ClipboardProvider::WlCopy => Box::new(CommandClipboard {
+            copy: |selection| match selection {
+                ClipboardSelection::Clipboard => vec!["wl-copy"],
+                ClipboardSelection::Primary => vec!["wl-copy", "--primary"],
+            },
+            paste: |selection| match selection {
+                ClipboardSelection::Clipboard => vec!["wl-paste", "--no-newline"],
+                ClipboardSelection::Primary => vec!["wl-paste", "--no-newline", "--primary"],
+            },
+        }),
+        ClipboardProvider::Xclip => Box::new(CommandClipboard {
+            copy: |selection| match selection {
+                ClipboardSelection::Clipboard => vec!["xclip", "-i", "-selection", "clipboard"],
+                ClipboardSelection::Primary => vec!["xclip", "-i", "-selection", "primary"],
+            },
+            paste: |selection| match selection {
+                ClipboardSelection::Clipboard => vec!["xclip", "-o", "-selection", "clipboard"],
+                ClipboardSelection::Primary => vec!["xclip", "-o", "-selection", "primary"],
+            },
+        }),
+        ClipboardProvider::Fake => Box::<FakeClipboard>::default(),
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|path| path.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+// Asks the terminal to set the clipboard, which also works over SSH.
+// Terminals don't let programs read it back, so pasting isn't supported.
+struct Osc52;
+
+impl Clipboard for Osc52 {
+    fn copy(&mut self, selection: ClipboardSelection, text: &str) -> io::Result<()> {
+        let target = match selection {
+            ClipboardSelection::Clipboard => 'c',
+            ClipboardSelection::Primary => 'p',
+        };
+
+        print!("\x1b]52;{target};{}\x07", base64(text.as_bytes()));
+        Terminal::flush()
+    }
+
+    fn paste(&mut self, _selection: ClipboardSelection) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+// Pipes the text through external tools like wl-copy and xclip
+struct CommandClipboard {
+    copy: fn(ClipboardSelection) -> Vec<&'static str>,
+    paste: fn(ClipboardSelection) -> Vec<&'static str>,
+}
+
+impl Clipboard for CommandClipboard {
+    fn copy(&mut self, selection: ClipboardSelection, text: &str) -> io::Result<()> {
+        let command = (self.copy)(selection);
+        let mut child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Wait for the tool even when it stopped reading, so it doesn't linger as a zombie
+        let written = match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(text.as_bytes()),
+            None => Ok(()),
+        };
+        let status = child.wait();
+
+        written?;
+        if !status?.success() {
+            return Err(io::Error::other(format!("{} failed", command[0])));
+        }
+        Ok(())
+    }
+
+    fn paste(&mut self, selection: ClipboardSelection) -> io::Result<Option<String>> {
+        let command = (self.paste)(selection);
+        let output = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+}
+
+// Keeps the clipboard in memory, for tests
+#[derive(Default)]
+pub struct FakeClipboard {
+    contents: HashMap<ClipboardSelection, String>,
+}
+
+impl Clipboard for FakeClipboard {
+    fn copy(&mut self, selection: ClipboardSelection, text: &str) -> io::Result<()> {
+        self.contents.insert(selection, text.to_string());
+        Ok(())
+    }
+
+    fn paste(&mut self, selection: ClipboardSelection) -> io::Result<Option<String>> {
+        Ok(self.contents.get(&selection).cloned())
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let indices = [
+            b[0] >> 2,
+            (b[0] & 0x03) << 4 | b[1] >> 4,
+            (b[1] & 0x0f) << 2 | b[2] >> 6,
+            b[2] & 0x3f,
+        ];
+
+        for (index, value) in indices.iter().enumerate() {
+            if index <= chunk.len() {
+                encoded.push(BASE64[*value as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_clipboard(copy: fn(ClipboardSelection) -> Vec<&'static str>) -> CommandClipboard {
+        CommandClipboard {
+            copy,
+            paste: |_| vec!["true"],
+        }
+    }
+
+    #[test]
+    fn copy_fails_when_the_tool_stops_reading() {
+        let mut clipboard = command_clipboard(|_| vec!["true"]);
+        let text = "x".repeat(1 << 20);
+
+        assert!(clipboard
+            .copy(ClipboardSelection::Clipboard, &text)
+            .is_err());
+    }
+
+    #[test]
+    fn copy_fails_when_the_tool_fails() {
+        let mut clipboard = command_clipboard(|_| vec!["sh", "-c", "cat > /dev/null; exit 1"]);
+
+        assert!(clipboard
+            .copy(ClipboardSelection::Clipboard, "text")
+            .is_err());
+    }
+
+    #[test]
+    fn copy_succeeds_once_the_tool_read_everything() {
+        let mut clipboard = command_clipboard(|_| vec!["sh", "-c", "cat > /dev/null"]);
+        let text = "x".repeat(1 << 20);
+
+        assert!(clipboard.copy(ClipboardSelection::Clipboard, &text).is_ok());
+    }
+}
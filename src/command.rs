@@ -1,19 +1,78 @@
+use crate::pattern;
+use crate::registers::Registers;
+use crate::Document;
+use regex::Regex;
 use std::collections::HashMap;
 
+// What a command does, the editor runs it
+#[derive(Clone, Copy, PartialEq)]
+pub enum CommandKind {
+    // A range without a command, like :42, goes to its last line
+    Goto,
+    Quit,
+    QuitAll,
+    Write,
+    WriteAll,
+    WriteQuit,
+    WriteQuitAll,
+    // Like :wq, but only writes when there are changes
+    Exit,
+    SaveAs,
+    Split,
+    VerticalSplit,
+    Close,
+    Only,
+    Set,
+    Edit,
+    NextBuffer,
+    PreviousBuffer,
+    Buffers,
+    DeleteBuffer,
+    Substitute,
+    Global,
+    VGlobal,
+    Delete,
+    NoHighlight,
+}
+
+// The lines a command works on when no range is given
+#[derive(Clone, Copy, PartialEq)]
+pub enum DefaultRange {
+    // The command doesn't take a range
+    None,
+    CurrentLine,
+    WholeFile,
+}
+
 pub struct Command {
     pub name: String,
     pub aliases: Vec<String>,
+    // Names of the arguments, optional ones are in brackets
     pub args: Vec<String>,
+    pub kind: CommandKind,
+    pub range: DefaultRange,
 }
 
 impl Command {
-    pub fn new(name: String, aliases: Vec<String>, args: Vec<String>) -> Self {
+    pub fn new(
+        name: &str,
+        aliases: &[&str],
+        args: &[&str],
+        kind: CommandKind,
+        range: DefaultRange,
+    ) -> Self {
         Self {
-            name,
-            aliases,
-            args,
+            name: name.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            kind,
+            range,
         }
     }
+
+    fn needs_argument(&self) -> bool {
+        self.args.iter().any(|arg| !arg.starts_with('['))
+    }
 }
 
 pub struct Commands {
@@ -22,24 +81,709 @@ pub struct Commands {
 
 impl Commands {
     pub fn new() -> Self {
-        let mut commands = HashMap::new();
+        let mut commands = Self {
+            commands: HashMap::new(),
+        };
+
+        commands.add(Command::new(
+            "quit",
+            &["q"],
+            &[],
+            CommandKind::Quit,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "qall",
+            &["qa", "quitall"],
+            &[],
+            CommandKind::QuitAll,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "write",
+            &["w", "save"],
+            &["[file]"],
+            CommandKind::Write,
+            DefaultRange::WholeFile,
+        ));
+        commands.add(Command::new(
+            "wall",
+            &["wa"],
+            &[],
+            CommandKind::WriteAll,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "wq",
+            &[],
+            &["[file]"],
+            CommandKind::WriteQuit,
+            DefaultRange::WholeFile,
+        ));
+        commands.add(Command::new(
+            "wqall",
+            &["wqa", "xall", "xa"],
+            &[],
+            CommandKind::WriteQuitAll,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "xit",
+            &["x", "exit"],
+            &["[file]"],
+            CommandKind::Exit,
+            DefaultRange::WholeFile,
+        ));
+        commands.add(Command::new(
+            "saveas",
+            &["sav"],
+            &["file"],
+            CommandKind::SaveAs,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "split",
+            &["sp"],
+            &["[file]"],
+            CommandKind::Split,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "vsplit",
+            &["vs"],
+            &["[file]"],
+            CommandKind::VerticalSplit,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "close",
+            &["clo"],
+            &[],
+            CommandKind::Close,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "only",
+            &["on"],
+            &[],
+            CommandKind::Only,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "set",
+            &["se"],
+            &["option"],
+            CommandKind::Set,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "edit",
+            &["e"],
+            &["file"],
+            CommandKind::Edit,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "bnext",
+            &["bn"],
+            &[],
+            CommandKind::NextBuffer,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "bprevious",
+            &["bp", "bprev"],
+            &[],
+            CommandKind::PreviousBuffer,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "buffers",
+            &["ls"],
+            &[],
+            CommandKind::Buffers,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "bdelete",
+            &["bd"],
+            &[],
+            CommandKind::DeleteBuffer,
+            DefaultRange::None,
+        ));
+        commands.add(Command::new(
+            "substitute",
+            &["s"],
+            &["[/pattern/replacement/flags]"],
+            CommandKind::Substitute,
+            DefaultRange::CurrentLine,
+        ));
+        commands.add(Command::new(
+            "global",
+            &["g"],
+            &["/pattern/[command]"],
+            CommandKind::Global,
+            DefaultRange::WholeFile,
+        ));
+        commands.add(Command::new(
+            "vglobal",
+            &["v"],
+            &["/pattern/[command]"],
+            CommandKind::VGlobal,
+            DefaultRange::WholeFile,
+        ));
+        commands.add(Command::new(
+            "delete",
+            &["d"],
+            &["[register]", "[count]"],
+            CommandKind::Delete,
+            DefaultRange::CurrentLine,
+        ));
+        commands.add(Command::new(
+            "nohlsearch",
+            &["noh"],
+            &[],
+            CommandKind::NoHighlight,
+            DefaultRange::None,
+        ));
+
+        commands
+    }
+
+    fn add(&mut self, command: Command) {
+        self.commands.insert(command.name.clone(), command);
+    }
+
+    // Finds a command by name, alias, or a prefix of a single name
+    pub fn find(&self, name: &str) -> Result<&Command, String> {
+        if let Some(command) = self.commands.values().find(|command| {
+            command.name == name || command.aliases.iter().any(|alias| alias == name)
+        }) {
+            return Ok(command);
+        }
+
+        let mut matches = self
+            .commands
+            .values()
+            .filter(|command| command.name.starts_with(name));
+        match (matches.next(), matches.next()) {
+            (Some(command), None) => Ok(command),
+            (Some(_), Some(_)) => Err(format!("E464: Ambiguous use of command: {name}")),
+            _ => Err(format!("E492: Not an editor command: {name}")),
+        }
+    }
+}
+
+// Line addresses of a range
+#[derive(Clone)]
+enum Address {
+    // 1 is the first line, 0 is before it
+    Number(usize),
+    Current,
+    Last,
+    Mark(char),
+    // The next line matching /pattern/, or the previous one matching ?pattern?
+    Search { pattern: String, forward: bool },
+}
+
+// An address followed by offsets like +5 or -, ".+5" or just "+5"
+#[derive(Clone)]
+struct LineSpec {
+    address: Option<Address>,
+    offset: isize,
+}
+
+impl LineSpec {
+    // Line number from 0 (before the first line) to the number of lines
+    fn resolve(
+        &self,
+        document: &Document,
+        current: usize,
+        ignore_case: &impl Fn(&str) -> bool,
+    ) -> Result<usize, String> {
+        let len = document.len();
+        let line = match &self.address {
+            None | Some(Address::Current) => current,
+            Some(Address::Number(line)) => *line,
+            Some(Address::Last) => len,
+            Some(Address::Mark(name)) => {
+                document
+                    .mark(*name)
+                    .ok_or_else(|| "E20: Mark not set".to_string())?
+                    .y
+                    + 1
+            }
+            Some(Address::Search { pattern, forward }) => {
+                let regex = pattern::compile(pattern, ignore_case(pattern))?;
+                search(document, &regex, current, *forward)
+                    .ok_or_else(|| format!("E486: Pattern not found: {pattern}"))?
+            }
+        };
+
+        let line = line
+            .checked_add_signed(self.offset)
+            .filter(|line| *line <= len)
+            .ok_or_else(|| "E16: Invalid range".to_string())?;
+        Ok(line)
+    }
+}
+
+// Lines after (or before) the current one, wrapping around the file
+fn search(document: &Document, regex: &Regex, current: usize, forward: bool) -> Option<usize> {
+    let len = document.len();
+    if len == 0 {
+        return None;
+    }
+
+    // current is a line number, rows start at 0
+    (1..=len)
+        .map(|step| {
+            if forward {
+                (current + step - 1) % len
+            } else {
+                (current + 2 * len - step - 1) % len
+            }
+        })
+        .find(|&y| {
+            document
+                .row(y)
+                .is_some_and(|row| regex.is_match(row.as_str()))
+        })
+        .map(|y| y + 1)
+}
 
-        commands.insert(
-            "quit".to_string(),
-            Command::new("quit".to_string(), vec!["q".to_string()], vec![]),
+#[derive(Clone)]
+struct Range {
+    start: LineSpec,
+    end: LineSpec,
+    // With ; the start becomes the current line for the end, as in 5;+2
+    from_start: bool,
+}
+
+// A command line like :'a,.+5s/old/new/g split into its range, command, bang and argument
+pub struct ParsedCommand {
+    range: Option<Range>,
+    default_range: DefaultRange,
+    pub kind: CommandKind,
+    pub bang: bool,
+    pub argument: String,
+}
+
+impl ParsedCommand {
+    // First and last rows (from 0) of the range, or of the default range of the command.
+    // ignore_case tells whether a /pattern/ of the range ignores case, as set in the editor.
+    pub fn rows(
+        &self,
+        document: &Document,
+        cursor_y: usize,
+        ignore_case: impl Fn(&str) -> bool,
+    ) -> Result<(usize, usize), String> {
+        let current = cursor_y + 1;
+        let (start, end) = match &self.range {
+            Some(range) => {
+                let start = range.start.resolve(document, current, &ignore_case)?;
+                let current = if range.from_start { start } else { current };
+                (start, range.end.resolve(document, current, &ignore_case)?)
+            }
+            None if self.default_range == DefaultRange::WholeFile => (1, document.len()),
+            None => (current, current),
+        };
+
+        // Line 0 is the first line for commands that work on lines
+        let (start, end) = (start.max(1), end.max(1));
+        if start <= end {
+            Ok((start - 1, end - 1))
+        } else {
+            Ok((end - 1, start - 1))
+        }
+    }
+}
+
+// Parses a command line, None when there is nothing to run
+pub fn parse(input: &str, commands: &Commands) -> Result<Option<ParsedCommand>, String> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        index: 0,
+    };
+
+    parser.skip(|c| c == ':' || c.is_whitespace());
+    let range = parser.range()?;
+    parser.skip(char::is_whitespace);
+
+    let name: String = parser.take(|c| c.is_ascii_alphabetic());
+    let bang = parser.eat('!');
+    let argument: String = parser.chars[parser.index..].iter().collect();
+    let argument = argument.trim().to_string();
+
+    if name.is_empty() {
+        if !argument.is_empty() {
+            return Err(format!("E492: Not an editor command: {}", input.trim()));
+        }
+        return Ok(range.map(|range| ParsedCommand {
+            range: Some(range),
+            default_range: DefaultRange::CurrentLine,
+            kind: CommandKind::Goto,
+            bang: false,
+            argument,
+        }));
+    }
+
+    let command = commands.find(&name)?;
+    if range.is_some() && command.range == DefaultRange::None {
+        return Err("E481: No range allowed".to_string());
+    }
+    if argument.is_empty() && command.needs_argument() {
+        return Err("E471: Argument required".to_string());
+    }
+
+    Ok(Some(ParsedCommand {
+        range,
+        default_range: command.range,
+        kind: command.kind,
+        bang,
+        argument,
+    }))
+}
+
+// The [x] [count] argument of :d, a digit starts the count and isn't a register
+pub fn register_and_count(argument: &str) -> Result<(Option<char>, Option<usize>), String> {
+    let mut chars = argument.chars();
+    let register = chars
+        .clone()
+        .next()
+        .filter(|&name| !name.is_ascii_digit() && Registers::is_valid(name));
+    if register.is_some() {
+        chars.next();
+    }
+
+    let count = chars.as_str().trim_start();
+    if count.is_empty() {
+        return Ok((register, None));
+    }
+    match count.parse() {
+        Ok(0) => Err("E939: Positive count required".to_string()),
+        Ok(count) => Ok((register, Some(count))),
+        Err(_) => Err(format!("E488: Trailing characters: {count}")),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.index += 1;
+            return true;
+        }
+        false
+    }
+
+    fn skip(&mut self, predicate: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&predicate) {
+            self.index += 1;
+        }
+    }
+
+    fn take(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let start = self.index;
+        self.skip(predicate);
+        self.chars[start..self.index].iter().collect()
+    }
+
+    // %, or one or two line specs separated by , or ;
+    fn range(&mut self) -> Result<Option<Range>, String> {
+        if self.eat('%') {
+            return Ok(Some(Range {
+                start: LineSpec {
+                    address: Some(Address::Number(1)),
+                    offset: 0,
+                },
+                end: LineSpec {
+                    address: Some(Address::Last),
+                    offset: 0,
+                },
+                from_start: false,
+            }));
+        }
+
+        let start = self.line_spec()?;
+        let from_start = match self.peek() {
+            Some(',') => false,
+            Some(';') => true,
+            _ => {
+                return Ok(start.map(|start| Range {
+                    end: start.clone(),
+                    start,
+                    from_start: false,
+                }))
+            }
+        };
+        self.index += 1;
+
+        // A missing address is the current line, as in ,5
+        let current = LineSpec {
+            address: Some(Address::Current),
+            offset: 0,
+        };
+        let start = start.unwrap_or(current.clone());
+        let end = self.line_spec()?.unwrap_or(current);
+        Ok(Some(Range {
+            start,
+            end,
+            from_start,
+        }))
+    }
+
+    fn line_spec(&mut self) -> Result<Option<LineSpec>, String> {
+        self.skip(char::is_whitespace);
+
+        let address = match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.take(|c| c.is_ascii_digit());
+                Some(Address::Number(
+                    digits
+                        .parse()
+                        .map_err(|_| "E16: Invalid range".to_string())?,
+                ))
+            }
+            Some('.') => {
+                self.index += 1;
+                Some(Address::Current)
+            }
+            Some('$') => {
+                self.index += 1;
+                Some(Address::Last)
+            }
+            Some('\'') => {
+                self.index += 1;
+                let name = self.peek().ok_or_else(|| "E20: Mark not set".to_string())?;
+                self.index += 1;
+                Some(Address::Mark(name))
+            }
+            Some(delimiter @ ('/' | '?')) => {
+                self.index += 1;
+                let pattern = self.pattern(delimiter);
+                if pattern.is_empty() {
+                    return Err("E35: No previous regular expression".to_string());
+                }
+                Some(Address::Search {
+                    pattern,
+                    forward: delimiter == '/',
+                })
+            }
+            _ => None,
+        };
+
+        // Offsets add up, a sign without a number counts as 1
+        let mut offset: isize = 0;
+        let mut has_offset = false;
+        loop {
+            self.skip(char::is_whitespace);
+            let sign = match self.peek() {
+                Some('+') => 1,
+                Some('-') => -1,
+                _ => break,
+            };
+            self.index += 1;
+            has_offset = true;
+
+            let digits = self.take(|c| c.is_ascii_digit());
+            let amount: isize = if digits.is_empty() {
+                1
+            } else {
+                digits
+                    .parse()
+                    .map_err(|_| "E16: Invalid range".to_string())?
+            };
+            offset = offset.saturating_add(sign * amount);
+        }
+
+        if address.is_none() && !has_offset {
+            return Ok(None);
+        }
+        Ok(Some(LineSpec { address, offset }))
+    }
+
+    // Text up to the closing delimiter or the end, \/ is a delimiter in the pattern
+    fn pattern(&mut self, delimiter: char) -> String {
+        let mut pattern = String::new();
+        while let Some(c) = self.peek() {
+            self.index += 1;
+            match c {
+                '\\' if self.peek() == Some(delimiter) => {
+                    pattern.push(delimiter);
+                    self.index += 1;
+                }
+                _ if c == delimiter => break,
+                _ => pattern.push(c),
+            }
+        }
+        pattern
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Position;
+
+    fn document(text: &str) -> Document {
+        let mut document = Document::default();
+        document.insert_str(&Position::new(0, 0), text);
+        document
+    }
+
+    fn parsed(input: &str) -> ParsedCommand {
+        parse(input, &Commands::new()).unwrap().unwrap()
+    }
+
+    fn error(input: &str) -> Option<String> {
+        parse(input, &Commands::new()).err()
+    }
+
+    fn rows(input: &str, document: &Document, cursor_y: usize) -> Result<(usize, usize), String> {
+        parsed(input).rows(document, cursor_y, |_| false)
+    }
+
+    #[test]
+    fn ranges_of_numbers_and_offsets() {
+        let document = document("1\n2\n3\n4\n5\n6\n7\n8\n9\n10");
+
+        assert_eq!(rows("d", &document, 3), Ok((3, 3)));
+        assert_eq!(rows("w", &document, 3), Ok((0, 9)));
+        assert_eq!(rows("%d", &document, 3), Ok((0, 9)));
+        assert_eq!(rows("2,4d", &document, 0), Ok((1, 3)));
+        assert_eq!(rows(".,+5d", &document, 2), Ok((2, 7)));
+        assert_eq!(rows(".,$d", &document, 6), Ok((6, 9)));
+        assert_eq!(rows("-2,+d", &document, 4), Ok((2, 5)));
+        assert_eq!(rows("++,$-1d", &document, 0), Ok((2, 8)));
+        assert_eq!(rows(",5d", &document, 1), Ok((1, 4)));
+        // Backwards ranges are turned around
+        assert_eq!(rows("4,2d", &document, 0), Ok((1, 3)));
+        assert_eq!(rows("0d", &document, 5), Ok((0, 0)));
+        assert_eq!(
+            rows("11d", &document, 0),
+            Err("E16: Invalid range".to_string())
+        );
+        assert_eq!(
+            rows(".-3d", &document, 1),
+            Err("E16: Invalid range".to_string())
         );
+    }
+
+    #[test]
+    fn semicolon_makes_the_start_the_current_line() {
+        let document = document("1\n2\n3\n4\n5\n6\n7\n8\n9\n10");
+
+        assert_eq!(rows("2;+1d", &document, 6), Ok((1, 2)));
+        assert_eq!(rows("2,+1d", &document, 6), Ok((1, 7)));
+        assert_eq!(rows("5;.d", &document, 0), Ok((4, 4)));
+    }
 
-        commands.insert(
-            "save".to_string(),
-            Command::new("save".to_string(), vec!["w".to_string()], vec![]),
+    #[test]
+    fn ranges_of_marks() {
+        let mut document = document("1\n2\n3\n4\n5");
+        document.set_mark('a', Position::new(0, 1));
+        document.set_mark('b', Position::new(2, 3));
+
+        assert_eq!(rows("'a,'bd", &document, 0), Ok((1, 3)));
+        assert_eq!(rows("'b,'ad", &document, 0), Ok((1, 3)));
+        assert_eq!(rows("'a+1d", &document, 0), Ok((2, 2)));
+        assert_eq!(
+            rows("'zd", &document, 0),
+            Err("E20: Mark not set".to_string())
         );
+    }
 
-        Self { commands }
+    #[test]
+    fn ranges_of_patterns() {
+        let document = document("foo\nbar\na/b\nfoo\nbaz");
+
+        assert_eq!(rows("/foo/d", &document, 0), Ok((3, 3)));
+        // Searches wrap around the end of the file
+        assert_eq!(rows("/foo/d", &document, 3), Ok((0, 0)));
+        assert_eq!(rows("?foo?d", &document, 3), Ok((0, 0)));
+        assert_eq!(rows("/ba/+1d", &document, 0), Ok((2, 2)));
+        assert_eq!(rows("/bar/,/baz/d", &document, 0), Ok((1, 4)));
+        assert_eq!(rows(r"/a\/b/d", &document, 0), Ok((2, 2)));
+        assert_eq!(
+            rows("/nothing/d", &document, 0),
+            Err("E486: Pattern not found: nothing".to_string())
+        );
+        assert_eq!(parsed("/FOO/d").rows(&document, 0, |_| true), Ok((3, 3)));
+        assert_eq!(
+            rows("/FOO/d", &document, 0),
+            Err("E486: Pattern not found: FOO".to_string())
+        );
+        assert_eq!(
+            error("//d"),
+            Some("E35: No previous regular expression".to_string())
+        );
     }
-}
 
-pub enum CommandResult {
-    Quit,
-    Save,
-    None,
+    #[test]
+    fn commands_by_name_alias_or_unique_prefix() {
+        assert!(parsed("substitute/a/b/").kind == CommandKind::Substitute);
+        assert!(parsed("s/a/b/").kind == CommandKind::Substitute);
+        assert!(parsed("subst/a/b/").kind == CommandKind::Substitute);
+        assert!(parsed("vs").kind == CommandKind::VerticalSplit);
+        assert!(parsed("noh").kind == CommandKind::NoHighlight);
+        assert!(parsed("bnext").kind == CommandKind::NextBuffer);
+        assert!(parsed("42").kind == CommandKind::Goto);
+
+        assert_eq!(
+            error("b"),
+            Some("E464: Ambiguous use of command: b".to_string())
+        );
+        assert_eq!(
+            error("frobnicate"),
+            Some("E492: Not an editor command: frobnicate".to_string())
+        );
+    }
+
+    #[test]
+    fn bang_argument_and_errors() {
+        let command = parsed(":  w! other.txt ");
+        assert!(command.kind == CommandKind::Write);
+        assert!(command.bang);
+        assert_eq!(command.argument, "other.txt");
+
+        let command = parsed("%s#a#b#g");
+        assert!(command.kind == CommandKind::Substitute);
+        assert!(!command.bang);
+        assert_eq!(command.argument, "#a#b#g");
+
+        assert!(parse("", &Commands::new()).unwrap().is_none());
+        assert_eq!(error("3q"), Some("E481: No range allowed".to_string()));
+        assert_eq!(error("e"), Some("E471: Argument required".to_string()));
+    }
+
+    #[test]
+    fn delete_register_and_count() {
+        assert_eq!(register_and_count(""), Ok((None, None)));
+        assert_eq!(register_and_count("a"), Ok((Some('a'), None)));
+        assert_eq!(register_and_count("a 3"), Ok((Some('a'), Some(3))));
+        assert_eq!(register_and_count("3"), Ok((None, Some(3))));
+        assert_eq!(register_and_count("_2"), Ok((Some('_'), Some(2))));
+        assert_eq!(
+            register_and_count("3x"),
+            Err("E488: Trailing characters: 3x".to_string())
+        );
+    }
+
+    #[test]
+    fn delete_count_of_zero() {
+        let positive = Err("E939: Positive count required".to_string());
+        assert_eq!(register_and_count("0"), positive);
+        assert_eq!(register_and_count("00"), positive);
+        assert_eq!(register_and_count("+0"), positive);
+        assert_eq!(register_and_count("a 0"), positive);
+    }
 }
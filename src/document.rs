@@ -1,80 +1,272 @@
+use crate::fileformat::{FileFormat, LineEnding};
+use crate::history::{self, Change, History};
+use crate::marks::Marks;
+use crate::swap::{self, SwapFile};
+use crate::utils;
 use crate::FileType;
 use crate::Position;
 use crate::Row;
 use crate::SearchDirection;
+use regex::Regex;
+use ropey::Rope;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
 use std::fs;
-use std::io::{Error, Write};
+use std::io::Error;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const SWAP_INTERVAL: Duration = Duration::from_secs(4);
+// Rows kept built at once, enough for several screens
+const MAX_CACHED_ROWS: usize = 1024;
 
 #[derive(Default)]
 pub struct Document {
     pub file_name: Option<String>,
-    rows: Vec<Row>,
+    // Rows joined with '\n', without a trailing newline
+    text: Rope,
+    // Rows by line, built from the text the first time they're needed
+    rows: RefCell<HashMap<usize, Rc<Row>>>,
+    // Changes the history doesn't know of, such as a new line ending
     dirty: bool,
+    // State of the history when the document was last written
+    saved_state: usize,
     file_type: FileType,
+    file_format: FileFormat,
+    // The file was a single empty line, which has no text either but is written with its line ending
+    empty_line: bool,
+    history: History,
+    marks: Marks,
+    // Whether there are changes that haven't been written to the swap file yet
+    swap_pending: bool,
+    // Another session may have the usual swap file
+    swap_file: SwapFile,
+    swapped_at: Option<Instant>,
 }
 
 impl Document {
     pub fn open(file_name: &str) -> Result<Self, std::io::Error> {
         let file_contents = fs::read_to_string(file_name)?;
-        let mut rows = Vec::new();
         let file_type = FileType::from(file_name);
+        let (file_format, lines) = FileFormat::detect(&file_contents);
 
-        for line in file_contents.lines() {
-            rows.push(Row::from(line));
-        }
-
-        Ok(Self {
+        let document = Self {
             file_name: Some(file_name.to_string()),
-            rows,
+            text: Rope::from_str(&lines.join("\n")),
+            rows: RefCell::default(),
             dirty: false,
+            saved_state: 0,
             file_type,
-        })
+            file_format,
+            empty_line: lines == [""],
+            history: History::default(),
+            marks: Marks::default(),
+            swap_pending: false,
+            swap_file: SwapFile::Usual,
+            swapped_at: None,
+        };
+
+        Ok(document)
     }
 
     pub fn open_non_existent(file_name: &str) -> Self {
         Self {
             file_name: Some(file_name.to_string()),
-            rows: Vec::new(),
+            text: Rope::new(),
+            rows: RefCell::default(),
             dirty: false,
+            saved_state: 0,
             file_type: FileType::from(file_name),
+            file_format: FileFormat::default(),
+            empty_line: false,
+            history: History::default(),
+            marks: Marks::default(),
+            swap_pending: false,
+            swap_file: SwapFile::Usual,
+            swapped_at: None,
         }
     }
 
-    pub fn save(&mut self) -> Result<(), Error> {
-        if let Some(file_name) = &self.file_name {
-            let mut file = fs::File::create(file_name)?;
+    pub fn save(&mut self, backup: bool) -> Result<(), Error> {
+        if let Some(file_name) = self.file_name.clone() {
+            self.save_as(&file_name, backup)?;
+        }
 
-            self.file_type = FileType::from(file_name);
+        Ok(())
+    }
 
-            for row in &mut self.rows {
-                file.write_all(row.as_bytes())?;
-                file.write_all(b"\n")?;
-            }
+    // Writes the document to a file, which becomes its file once it is written
+    pub fn save_as(&mut self, file_name: &str, backup: bool) -> Result<(), Error> {
+        self.write(file_name, 0, self.len().saturating_sub(1), backup)?;
 
-            self.dirty = false;
+        // The swap file goes with the old name
+        self.remove_swap();
+        if self.file_name.as_deref() != Some(file_name) {
+            self.swap_file = SwapFile::Usual;
         }
+        self.file_name = Some(file_name.to_string());
+        self.file_type = FileType::from(file_name);
+        self.dirty = false;
+        self.saved_state = self.history.state();
+        self.swap_pending = false;
 
         Ok(())
     }
 
-    pub fn row(&self, index: usize) -> Option<&Row> {
-        self.rows.get(index)
+    // Writes the rows from first to last to a file, in the format of the document
+    pub fn write(
+        &self,
+        file_name: &str,
+        first: usize,
+        last: usize,
+        backup: bool,
+    ) -> Result<(), Error> {
+        let line_ending = self.file_format.line_ending.as_str();
+        let start = self.char_index(&Position::new(0, first));
+        let end = cmp::max(
+            start,
+            self.char_index(&Position::new(self.row_len(last), last)),
+        );
+
+        utils::write_atomically(file_name, backup, |file| {
+            file.write_all(self.file_format.bom().as_bytes())?;
+            for chunk in self.text.slice(start..end).chunks() {
+                file.write_all(chunk.replace('\n', line_ending).as_bytes())?;
+            }
+            if (!self.is_empty() || self.empty_line) && self.file_format.final_newline {
+                file.write_all(line_ending.as_bytes())?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn row(&self, index: usize) -> Option<Rc<Row>> {
+        if index >= self.len() {
+            return None;
+        }
+        if let Some(row) = self.rows.borrow().get(&index) {
+            return Some(Rc::clone(row));
+        }
+
+        let row = Rc::new(Row::from(self.line(index).as_str()));
+        let mut rows = self.rows.borrow_mut();
+        // Start over rather than keep every row of a large document
+        if rows.len() >= MAX_CACHED_ROWS {
+            rows.clear();
+        }
+        rows.insert(index, Rc::clone(&row));
+        Some(row)
+    }
+
+    // Length of a row in graphemes, without building the row when possible
+    pub fn row_len(&self, index: usize) -> usize {
+        if index >= self.len() {
+            return 0;
+        }
+
+        let line = self.text.line(index);
+        let newline = usize::from(index + 1 < self.text.len_lines());
+
+        // ASCII lines have one grapheme per char
+        if line.len_bytes() == line.len_chars() {
+            line.len_chars() - newline
+        } else {
+            self.row(index).map_or(0, |row| row.len())
+        }
     }
 
     pub fn file_type(&self) -> String {
         self.file_type.name()
     }
 
+    pub fn file_format(&self) -> &FileFormat {
+        &self.file_format
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.file_format.line_ending != line_ending {
+            self.file_format.line_ending = line_ending;
+            self.mark_dirty();
+        }
+    }
+
+    // Writes unsaved changes to the swap file, at most once every SWAP_INTERVAL unless forced
+    pub fn update_swap(&mut self, force: bool) -> Result<(), Error> {
+        let path = match self.swap_path() {
+            Some(path) if self.is_dirty() && self.swap_pending => path,
+            _ => return Ok(()),
+        };
+
+        if !force
+            && self
+                .swapped_at
+                .is_some_and(|time| time.elapsed() < SWAP_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        swap::write(&path, &self.text.to_string())?;
+        self.swap_pending = false;
+        self.swapped_at = Some(Instant::now());
+
+        Ok(())
+    }
+
+    pub fn has_swap(&self) -> bool {
+        self.file_name.as_deref().is_some_and(swap::exists)
+    }
+
+    // Removes the swap file of this session, never the one of another session
+    pub fn remove_swap(&self) {
+        if let Some(path) = self.swap_path() {
+            swap::remove(&path);
+        }
+    }
+
+    // Leaves the swap file found next to the file to the session it belongs to
+    pub fn use_other_swap(&mut self) {
+        self.swap_file = match self.file_name.as_deref().and_then(swap::free_path) {
+            Some(path) => SwapFile::Other(path),
+            None => SwapFile::Disabled,
+        };
+    }
+
+    fn swap_path(&self) -> Option<PathBuf> {
+        match &self.swap_file {
+            SwapFile::Usual => self.file_name.as_deref().map(swap::path),
+            SwapFile::Other(path) => Some(path.clone()),
+            SwapFile::Disabled => None,
+        }
+    }
+
+    // Replaces the contents with the ones left in the swap file by a previous session
+    pub fn recover_swap(&mut self) -> Result<(), Error> {
+        if let Some(file_name) = &self.file_name {
+            self.text = Rope::from_str(&swap::read(file_name)?);
+            self.reset_rows();
+            self.history = History::default();
+            self.mark_dirty();
+        }
+
+        Ok(())
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.rows.is_empty()
+        self.text.len_chars() == 0
     }
 
     pub fn is_dirty(&self) -> bool {
-        self.dirty
+        self.dirty || self.history.state() != self.saved_state
     }
 
     pub fn len(&self) -> usize {
-        self.rows.len()
+        if self.is_empty() {
+            0
+        } else {
+            self.text.len_lines()
+        }
     }
 
     pub fn insert_newline(&mut self, at: &Position) {
@@ -82,16 +274,7 @@ impl Document {
             return;
         }
 
-        // Cursor at the end of the line, just insert a new empty row
-        if at.y == self.len() {
-            self.rows.push(Row::default());
-        }
-
-        // Otherwise split the line at the cursor position and insert a new row containing the right half
-        let current_row = self.rows.get_mut(at.y).unwrap();
-        let new_row = current_row.split(at.x);
-
-        self.rows.insert(at.y + 1, new_row);
+        self.edit(at, "\n");
     }
 
     pub fn insert(&mut self, at: &Position, c: char) {
@@ -99,16 +282,7 @@ impl Document {
             return;
         }
 
-        self.dirty = true;
-
-        if at.y == self.rows.len() {
-            let mut new_row = Row::default();
-            new_row.insert(0, c);
-            self.rows.push(new_row);
-        } else {
-            let row = self.rows.get_mut(at.y).unwrap();
-            row.insert(at.x, c);
-        }
+        self.edit(at, &c.to_string());
     }
 
     pub fn delete(&mut self, at: &Position) {
@@ -119,57 +293,328 @@ impl Document {
             return;
         }
 
-        self.dirty = true;
+        let width = self.row_len(at.y);
 
         // If we're at the end of a line and there's a line after, append them together
-        if at.x == self.rows.get_mut(at.y).unwrap().len() && at.y + 1 < len {
-            let next_row = self.rows.remove(at.y + 1);
-            let row = self.rows.get_mut(at.y).unwrap();
-            row.append(&next_row);
+        // Otherwise, just delete the single character
+        let end = if at.x >= width && at.y + 1 < len {
+            Position::new(0, at.y + 1)
+        } else if at.x < width {
+            Position::new(at.x + 1, at.y)
         } else {
-            // Otherwise, just delete the single character
-            let row = self.rows.get_mut(at.y).unwrap();
-            row.delete(at.x);
+            return;
+        };
+
+        let text = self.delete_text(at, &end);
+        self.swap_pending = true;
+        self.history.record(Change::Delete {
+            at: at.clone(),
+            text,
+        });
+    }
+
+    // Inserts text that may span several rows and returns the position right after it
+    pub fn insert_str(&mut self, at: &Position, text: &str) -> Position {
+        if text.is_empty() || at.y > self.len() {
+            return at.clone();
         }
+
+        self.edit(at, text);
+        history::end_position(at, text)
     }
 
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
-        if at.y > self.rows.len() {
-            return None;
+    // Deletes the text between start (inclusive) and end (exclusive) and returns it
+    pub fn delete_range(&mut self, start: &Position, end: &Position) -> String {
+        if start >= end {
+            return String::new();
         }
 
-        let mut position = Position { x: at.x, y: at.y };
+        let text = self.delete_text(start, end);
+        if !text.is_empty() {
+            self.swap_pending = true;
+            self.history.record(Change::Delete {
+                at: start.clone(),
+                text: text.clone(),
+            });
+        }
+        text
+    }
 
-        // If forward search, search from current pos to end of file
-        // Else search from start of file to current pos
-        let start = if direction == SearchDirection::Forward {
-            at.y
-        } else {
-            0
-        };
-        let end = if direction == SearchDirection::Forward {
-            self.rows.len()
+    pub fn text_range(&self, start: &Position, end: &Position) -> String {
+        let start = self.char_index(start);
+        let end = cmp::max(start, self.char_index(end));
+
+        self.text.slice(start..end).to_string()
+    }
+
+    // Group every following change into a single undo step, until commit_transaction is called
+    pub fn begin_transaction(&mut self, cursor: &Position) {
+        self.history.begin(cursor);
+    }
+
+    pub fn commit_transaction(&mut self) {
+        self.history.commit();
+    }
+
+    pub fn undo_steps(&self) -> usize {
+        self.history.len()
+    }
+
+    // Turns the changes made since there were `steps` undo steps into a single one
+    pub fn merge_undo_steps(&mut self, steps: usize) {
+        self.history.merge_since(steps);
+    }
+
+    // Reverts the last transaction and returns where the cursor should go
+    pub fn undo(&mut self) -> Option<Position> {
+        let transaction = self.history.undo()?;
+        let cursor = transaction.cursor().clone();
+        let changes: Vec<Change> = transaction
+            .changes()
+            .iter()
+            .rev()
+            .map(Change::inverse)
+            .collect();
+
+        for change in &changes {
+            self.apply(change);
+        }
+        self.swap_pending = true;
+
+        Some(cursor)
+    }
+
+    pub fn redo(&mut self) -> Option<Position> {
+        let transaction = self.history.redo()?;
+        let changes: Vec<Change> = transaction.changes().to_vec();
+        let cursor = changes.first()?.position().clone();
+
+        for change in &changes {
+            self.apply(change);
+        }
+        self.swap_pending = true;
+
+        Some(cursor)
+    }
+
+    pub fn mark(&self, name: char) -> Option<&Position> {
+        self.marks.get(name)
+    }
+
+    pub fn set_mark(&mut self, name: char, position: Position) {
+        self.marks.set(name, position);
+    }
+
+    pub fn remove_mark(&mut self, name: char) -> Option<Position> {
+        self.marks.remove(name)
+    }
+
+    // Rows that keep their place as lines are added or removed above them, for :g
+    pub fn mark_lines(&mut self, rows: &[usize]) {
+        self.marks.set_lines(rows);
+    }
+
+    pub fn next_marked_line(&mut self) -> Option<usize> {
+        self.marks.next_line()
+    }
+
+    pub fn clear_marked_lines(&mut self) {
+        self.marks.clear_lines();
+    }
+
+    pub fn global_marks(&self) -> impl Iterator<Item = (char, &Position)> {
+        self.marks.global()
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.swap_pending = true;
+    }
+
+    // Inserts text and records it in the history
+    fn edit(&mut self, at: &Position, text: &str) {
+        let len = self.len();
+
+        // Typing past the last row appends a new row after it
+        let (at, text) = if at.y == len && len > 0 {
+            (
+                Position::new(self.row_len(len - 1), len - 1),
+                format!("\n{text}"),
+            )
         } else {
-            at.y + 1
+            (at.clone(), text.to_string())
         };
 
-        for _ in start..end {
-            if let Some(row) = self.rows.get(position.y) {
-                if let Some(x) = row.find(query, position.x, direction) {
-                    position.x = x;
-                    return Some(position);
-                }
-                if direction == SearchDirection::Forward {
-                    position.y += 1;
-                    position.x = 0;
-                } else {
-                    position.y -= 1;
-                    position.x = self.rows[position.y].len();
-                }
-            } else {
-                return None;
+        self.insert_text(&at, &text);
+        self.swap_pending = true;
+        self.history.record(Change::Insert { at, text });
+    }
+
+    fn apply(&mut self, change: &Change) {
+        match change {
+            Change::Insert { at, text } => self.insert_text(at, text),
+            Change::Delete { at, text } => {
+                let end = history::end_position(at, text);
+                self.delete_text(at, &end);
             }
         }
-        None
+    }
+
+    fn insert_text(&mut self, at: &Position, text: &str) {
+        let index = self.char_index(at);
+        self.text.insert(index, text);
+        self.rows_changed(at.y, at.y, text.contains('\n'));
+        self.marks.inserted(at, &history::end_position(at, text));
+    }
+
+    // Removes the text between start (inclusive) and end (exclusive) and returns it
+    fn delete_text(&mut self, start: &Position, end: &Position) -> String {
+        let range = self.char_index(start)..self.char_index(end);
+        let removed = self.text.slice(range.clone()).to_string();
+        self.marks.deleted(start, end, self.row_len(end.y));
+
+        self.text.remove(range);
+        self.rows_changed(start.y, end.y, start.y != end.y);
+        removed
+    }
+
+    // Content of a row, without its line break
+    fn line(&self, index: usize) -> String {
+        let mut line = self.text.line(index).to_string();
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        line
+    }
+
+    // Converts a grapheme position to a char index in the rope
+    fn char_index(&self, at: &Position) -> usize {
+        if at.y >= self.text.len_lines() {
+            return self.text.len_chars();
+        }
+
+        let start = self.text.line_to_char(at.y);
+        let x = cmp::min(at.x, self.row_len(at.y));
+        let line = self.text.line(at.y);
+
+        if line.len_bytes() == line.len_chars() {
+            start + x
+        } else {
+            start + self.row(at.y).map_or(x, |row| row.char_offset(x))
+        }
+    }
+
+    fn reset_rows(&mut self) {
+        self.rows.get_mut().clear();
+    }
+
+    // Drops the cached rows from first to last, which an edit replaced, and the ones after
+    // them when the edit added or removed rows
+    fn rows_changed(&mut self, first: usize, last: usize, moved: bool) {
+        self.rows
+            .get_mut()
+            .retain(|&index, _| index < first || (!moved && index > last));
+    }
+
+    // First match of a regex from `at` on, or the last one before it, without wrapping around
+    pub fn find(
+        &self,
+        regex: &Regex,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<Position> {
+        let last = self.len().checked_sub(1)?;
+
+        match direction {
+            SearchDirection::Forward => (at.y..=last).find_map(|y| {
+                let x = if y == at.y { at.x } else { 0 };
+                let x = self.row(y)?.find(regex, x, direction)?;
+                Some(Position::new(x, y))
+            }),
+            SearchDirection::Backward => (0..=cmp::min(at.y, last)).rev().find_map(|y| {
+                // Every match of the rows above
+                let x = if y == at.y { at.x } else { usize::MAX };
+                let x = self.row(y)?.find(regex, x, direction)?;
+                Some(Position::new(x, y))
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(text: &str) -> Document {
+        let mut document = Document::default();
+        document.insert_str(&Position::new(0, 0), text);
+        document
+    }
+
+    // Contents of a file once it has been opened and written back
+    fn round_trip(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("miv-{}-{name}", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, contents).unwrap();
+
+        let mut document = Document::open(&file_name).unwrap();
+        document.save(false).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        written
+    }
+
+    fn rows(document: &Document) -> Vec<String> {
+        (0..document.len())
+            .filter_map(|y| document.row(y))
+            .map(|row| row.as_str().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn rows_after_an_edit_on_a_row_are_kept() {
+        let mut document = document("one\ntwo\nthree");
+        rows(&document);
+
+        document.insert_str(&Position::new(3, 1), "!");
+        assert_eq!(rows(&document), ["one", "two!", "three"]);
+    }
+
+    #[test]
+    fn rows_move_with_inserted_and_deleted_line_breaks() {
+        let mut document = document("one\ntwo\nthree");
+        rows(&document);
+
+        document.insert_str(&Position::new(1, 0), "\nx\n");
+        assert_eq!(rows(&document), ["o", "x", "ne", "two", "three"]);
+
+        document.delete_range(&Position::new(1, 0), &Position::new(0, 2));
+        assert_eq!(rows(&document), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn undo_restores_the_rows() {
+        let mut document = document("one\ntwo");
+        document.delete_range(&Position::new(0, 0), &Position::new(1, 1));
+        assert_eq!(rows(&document), ["wo"]);
+
+        document.undo();
+        assert_eq!(rows(&document), ["one", "two"]);
+    }
+
+    #[test]
+    fn a_lone_line_ending_is_written_back() {
+        assert_eq!(round_trip("unix", "\n"), "\n");
+        assert_eq!(round_trip("dos", "\r\n"), "\r\n");
+    }
+
+    #[test]
+    fn an_empty_file_stays_empty() {
+        assert_eq!(round_trip("empty", ""), "");
+    }
+
+    #[test]
+    fn a_missing_final_line_ending_is_not_added() {
+        assert_eq!(round_trip("noeol", "one\r\ntwo"), "one\r\ntwo");
     }
 }
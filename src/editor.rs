@@ -1,19 +1,42 @@
+use crate::buffer::Buffer;
+use crate::clipboard;
+use crate::command::{self, CommandKind, Commands};
+use crate::fileformat::LineEnding;
+use crate::keymaps::{self, Action, Count, Direction, MovementType, Parsed};
+use crate::layout::{Layout, Rect, Side, SplitDirection};
+use crate::marks::Marks;
+use crate::motion;
+use crate::operator::Operator;
+use crate::pattern;
+use crate::registers::{Register, Registers};
+use crate::selection::{Selection, SelectionKind};
+use crate::substitute::{Flags, Substitute};
+use crate::utils;
+use crate::window::Window;
 use crate::{Document, Highlighting, Mode, Position, PossibleModes, Row, Settings, Terminal};
 use crossterm::{
     cursor,
-    event::{KeyCode, KeyModifiers},
+    event::{KeyCode, KeyEvent, KeyModifiers},
     execute,
-    style::Stylize,
+    style::{Attribute, Color, Stylize},
     terminal::{Clear, ClearType},
 };
+use regex::Regex;
 use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::io::stdout;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
 use std::time::{Duration, Instant};
 use syntect::{easy::HighlightLines, highlighting::Style, util::as_24_bit_terminal_escaped};
+use unicode_segmentation::UnicodeSegmentation;
 
 const EDITOR_VERSION: &str = env!("CARGO_PKG_VERSION");
 const QUIT_TIMES: u8 = 2;
+const MAX_REPLAYED_KEYS: usize = 1_000_000;
+const MAX_JUMPS: usize = 100;
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -35,12 +58,68 @@ impl StatusMessage {
     }
 }
 
+// A change that . can repeat: the command, and what was typed in the Insert mode it started
+#[derive(Clone)]
+struct Change {
+    action: Action,
+    register: Option<char>,
+    inserted: Vec<KeyEvent>,
+}
+
 pub struct Editor {
     should_quit: bool,
     terminal: Terminal,
+    // The buffer being edited is kept here, every other open buffer is parked in `buffers`
     document: Document,
+    buffer_id: usize,
+    buffers: Vec<Buffer>,
+    next_buffer_id: usize,
+    // Same for the current window, other windows are parked in `windows`, most recently used last
     cursor_position: Position,
     offset: Position,
+    window_id: usize,
+    windows: Vec<Window>,
+    next_window_id: usize,
+    layout: Layout,
+    // Where the selection started in visual modes
+    visual_anchor: Position,
+    registers: Registers,
+    // Keys of a command that isn't complete yet, like the d of dw
+    pending_keys: Vec<KeyEvent>,
+    // Keys typed since entering Insert mode, and how many times to insert them
+    insert_keys: Vec<KeyEvent>,
+    insert_count: usize,
+    insert_opens_line: bool,
+    // What each key typed in Replace mode overwrote, None when it added text
+    replaced: Vec<Option<String>>,
+    // The last change for ., and the one being made
+    last_change: Option<Change>,
+    pending_change: Option<Change>,
+    // Register and keys of the macro being recorded, keys of macros being replayed
+    recording: Option<(char, Vec<KeyEvent>)>,
+    replay_keys: VecDeque<KeyEvent>,
+    // Keys replayed since the last typed key, including the ones of macros called by macros
+    replayed_keys: usize,
+    // Keys typed while a macro was replayed
+    typeahead: VecDeque<KeyEvent>,
+    last_macro: Option<char>,
+    // Last f, F, t or T, for ; and ,
+    last_find: Option<(MovementType, Direction)>,
+    // Global marks of files that were closed
+    file_marks: HashMap<char, (String, Position)>,
+    // Buffers and positions before big jumps, oldest first, and where Ctrl-o and Ctrl-i are in it
+    jumps: Vec<(usize, Position)>,
+    jump_index: usize,
+    commands: Commands,
+    // The last :s, repeated by :s without an argument, and the pattern of an empty one
+    last_substitute: Option<Substitute>,
+    last_pattern: Option<String>,
+    // The match :s asks about, shown like a selection
+    highlighted_match: Option<Selection>,
+    // Matches of the last search, highlighted until :nohlsearch
+    search_highlight: Option<Regex>,
+    // Direction of the last / or ?, for n and N
+    last_search_direction: SearchDirection,
     status_message: StatusMessage,
     quit_times: u8,
     mode: Mode,
@@ -51,36 +130,116 @@ pub struct Editor {
 impl Editor {
     pub fn new(config: Settings) -> Self {
         let args: Vec<String> = env::args().collect();
-        let mut initial_status =
-            String::from("HELP: Ctrl-Q = quit | Ctrl-S = save | Ctrl+f = search");
-
-        let document = if args.len() > 1 {
-            match Document::open(&args[1]) {
-                Ok(doc) => doc,
-                Err(e) => {
-                    initial_status = format!("ERROR: Could not open file: {e}");
-                    Document::open_non_existent(&args[1])
-                }
-            }
-        } else {
-            Document::default()
-        };
+        let initial_status = String::from("HELP: Ctrl-Q = quit | Ctrl-S = save | Ctrl+f = search");
 
-        Self {
+        let mut editor = Self {
             should_quit: false,
             terminal: Terminal::new().expect("Failed to initialize terminal"),
-            document,
+            document: Document::default(),
+            buffer_id: 1,
+            buffers: Vec::new(),
+            next_buffer_id: 2,
             cursor_position: Position::default(),
             offset: Position::default(),
+            window_id: 1,
+            windows: Vec::new(),
+            next_window_id: 2,
+            layout: Layout::new(1),
+            visual_anchor: Position::default(),
+            registers: Registers::new(clipboard::new(config.editor.clipboard)),
+            pending_keys: Vec::new(),
+            insert_keys: Vec::new(),
+            insert_count: 1,
+            insert_opens_line: false,
+            replaced: Vec::new(),
+            last_change: None,
+            pending_change: None,
+            recording: None,
+            replay_keys: VecDeque::new(),
+            replayed_keys: 0,
+            typeahead: VecDeque::new(),
+            last_macro: None,
+            last_find: None,
+            file_marks: HashMap::new(),
+            jumps: Vec::new(),
+            jump_index: 0,
+            commands: Commands::new(),
+            last_substitute: None,
+            last_pattern: None,
+            highlighted_match: None,
+            search_highlight: None,
+            last_search_direction: SearchDirection::Forward,
             status_message: StatusMessage::from(initial_status),
             quit_times: QUIT_TIMES,
             mode: Mode::default(),
             highlighting: Highlighting::default(),
             config,
+        };
+
+        for file_name in args.iter().skip(1) {
+            editor.open_file(file_name);
+        }
+
+        // Start on the first file given on the command line
+        if let Some(index) = editor
+            .buffers
+            .iter()
+            .position(|buffer| buffer.id < editor.buffer_id)
+        {
+            let buffer = editor.buffers.remove(index);
+            editor.switch_to_buffer(buffer);
         }
+
+        editor
     }
 
     pub fn run(&mut self) {
+        self.update_title();
+
+        // Main loop of the editor
+        loop {
+            // Macros are replayed without redrawing after every key
+            if self.replay_keys.is_empty() {
+                if let Err(e) = self.refresh_screen() {
+                    die(e);
+                }
+            }
+
+            if self.should_quit {
+                self.document.remove_swap();
+                for buffer in &self.buffers {
+                    buffer.document.remove_swap();
+                }
+                Terminal::restore_defaults();
+                break;
+            }
+
+            if let Err(e) = self.process_keypress() {
+                die(e);
+            }
+
+            // Parked buffers can have unsaved changes too
+            let mut swapped = self.document.update_swap(false);
+            for buffer in &mut self.buffers {
+                swapped = swapped.and(buffer.document.update_swap(false));
+            }
+            if swapped.is_err() {
+                self.status_message =
+                    StatusMessage::from("ERROR: Could not write swap file!".to_string());
+            }
+        }
+    }
+
+    // Called when the editor panicked, so unsaved changes can be recovered on the next start
+    pub fn write_swap_file(&mut self) {
+        let _ = self.document.update_swap(true);
+        for buffer in &mut self.buffers {
+            let _ = buffer.document.update_swap(true);
+        }
+        Terminal::restore_defaults();
+    }
+
+    fn update_title(&self) {
         Terminal::set_title(&format!(
             "{} — Miv {}",
             self.document
@@ -89,36 +248,474 @@ impl Editor {
                 .unwrap_or(String::from("[No Name]")),
             EDITOR_VERSION,
         ));
+    }
 
-        // Main loop of the editor
-        loop {
-            if let Err(e) = self.refresh_screen() {
-                die(e);
+    fn is_any_buffer_dirty(&self) -> bool {
+        self.document.is_dirty() || self.buffers.iter().any(|buffer| buffer.document.is_dirty())
+    }
+
+    // Switches to the buffer editing the given file, opening it if needed
+    fn open_file(&mut self, file_name: &str) {
+        if self.document.file_name.as_deref() == Some(file_name) {
+            return;
+        }
+
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.document.file_name.as_deref() == Some(file_name))
+        {
+            let buffer = self.buffers.remove(index);
+            self.switch_to_buffer(buffer);
+            return;
+        }
+
+        let document = match Document::open(file_name) {
+            Ok(document) => document,
+            Err(e) => {
+                self.status_message =
+                    StatusMessage::from(format!("ERROR: Could not open file: {e}"));
+                Document::open_non_existent(file_name)
             }
+        };
+        // Reuse the initial empty buffer instead of keeping it around
+        if self.document.file_name.is_none()
+            && self.document.is_empty()
+            && !self.document.is_dirty()
+        {
+            self.load_buffer(Buffer::new(self.buffer_id, document));
+            self.update_title();
+        } else {
+            let buffer = Buffer::new(self.next_buffer_id, document);
+            self.next_buffer_id += 1;
+            self.switch_to_buffer(buffer);
+        }
 
-            if self.should_quit {
-                Terminal::restore_defaults();
-                break;
+        self.check_swap_file();
+    }
+
+    // Makes the given buffer the current one and returns the buffer that was current before
+    fn load_buffer(&mut self, buffer: Buffer) -> Buffer {
+        let previous = Buffer {
+            id: self.buffer_id,
+            document: mem::replace(&mut self.document, buffer.document),
+            cursor_position: mem::replace(&mut self.cursor_position, buffer.cursor_position),
+            offset: mem::replace(&mut self.offset, buffer.offset),
+        };
+        self.buffer_id = buffer.id;
+
+        previous
+    }
+
+    fn switch_to_buffer(&mut self, buffer: Buffer) {
+        let previous = self.load_buffer(buffer);
+        let index = self
+            .buffers
+            .partition_point(|buffer| buffer.id < previous.id);
+
+        self.buffers.insert(index, previous);
+        self.update_title();
+    }
+
+    fn cycle_buffer(&mut self, direction: SearchDirection) {
+        if self.buffers.is_empty() {
+            self.status_message = StatusMessage::from("There is only one buffer".to_string());
+            return;
+        }
+
+        // Buffers are sorted by id, wrap around at both ends
+        let index = match direction {
+            SearchDirection::Forward => self
+                .buffers
+                .iter()
+                .position(|buffer| buffer.id > self.buffer_id)
+                .unwrap_or(0),
+            SearchDirection::Backward => self
+                .buffers
+                .iter()
+                .rposition(|buffer| buffer.id < self.buffer_id)
+                .unwrap_or(self.buffers.len() - 1),
+        };
+
+        let buffer = self.buffers.remove(index);
+        self.switch_to_buffer(buffer);
+    }
+
+    fn delete_buffer(&mut self, force: bool) {
+        if !force && self.document.is_dirty() {
+            self.status_message = StatusMessage::from(format!(
+                "No write since last change for buffer {} (add ! to override)",
+                self.buffer_id
+            ));
+            return;
+        }
+
+        self.document.remove_swap();
+
+        // Global marks are remembered with their file, jumps to the buffer go away
+        if let Some(file_name) = &self.document.file_name {
+            for (name, position) in self.document.global_marks() {
+                self.file_marks
+                    .insert(name, (file_name.clone(), position.clone()));
             }
+        }
+        let buffer_id = self.buffer_id;
+        self.jumps.retain(|(id, _)| *id != buffer_id);
+        self.jump_index = self.jumps.len();
+
+        // Close the other windows that show the deleted buffer
+        for window in self
+            .windows
+            .iter()
+            .filter(|window| window.buffer_id == buffer_id)
+        {
+            self.layout.remove(window.id);
+        }
+        self.windows.retain(|window| window.buffer_id != buffer_id);
 
-            if let Err(e) = self.process_keypress() {
-                die(e);
+        if self.buffers.is_empty() {
+            let buffer = Buffer::new(self.next_buffer_id, Document::default());
+            self.next_buffer_id += 1;
+            self.load_buffer(buffer);
+        } else {
+            let index = self
+                .buffers
+                .iter()
+                .position(|buffer| buffer.id > self.buffer_id)
+                .unwrap_or(self.buffers.len() - 1);
+            let buffer = self.buffers.remove(index);
+            self.load_buffer(buffer);
+        }
+
+        self.update_title();
+    }
+
+    fn list_buffers(&mut self) -> Result<(), std::io::Error> {
+        let mut buffers: Vec<(usize, &Document, &Position)> = self
+            .buffers
+            .iter()
+            .map(|buffer| (buffer.id, &buffer.document, &buffer.cursor_position))
+            .collect();
+        buffers.push((self.buffer_id, &self.document, &self.cursor_position));
+        buffers.sort_by_key(|(id, _, _)| *id);
+
+        let lines: Vec<String> = buffers
+            .iter()
+            .map(|(id, document, cursor_position)| {
+                format!(
+                    "{:>3} {}{} \"{}\" line {}",
+                    id,
+                    if *id == self.buffer_id { "%" } else { " " },
+                    if document.is_dirty() { "+" } else { " " },
+                    document
+                        .file_name
+                        .clone()
+                        .unwrap_or(String::from("[No Name]")),
+                    cursor_position.y.saturating_add(1)
+                )
+            })
+            .collect();
+
+        self.display_list(&lines)
+    }
+
+    // Shows lines at the bottom of the screen until a key is pressed
+    fn display_list(&mut self, lines: &[String]) -> Result<(), std::io::Error> {
+        let width = self.terminal.size().width as usize;
+        // The terminal size doesn't include the message bar
+        let screen_height = self.terminal.size().height as usize + 1;
+        let start = screen_height.saturating_sub(lines.len() + 1);
+
+        for (index, line) in lines.iter().enumerate() {
+            let mut line = line.clone();
+            line.truncate(width);
+
+            Terminal::set_cursor_position(&Position::new(0, start + index));
+            Terminal::clear_current_line();
+            print!("{line}");
+        }
+
+        Terminal::set_cursor_position(&Position::new(0, screen_height.saturating_sub(1)));
+        Terminal::clear_current_line();
+        print!("Press any key to continue");
+        Terminal::flush()?;
+
+        self.read_key()?;
+        Ok(())
+    }
+
+    fn buffer_document(&self, buffer_id: usize) -> Option<&Document> {
+        if buffer_id == self.buffer_id {
+            Some(&self.document)
+        } else {
+            self.buffers
+                .iter()
+                .find(|buffer| buffer.id == buffer_id)
+                .map(|buffer| &buffer.document)
+        }
+    }
+
+    // Makes the buffer with the given id the current one
+    fn show_buffer(&mut self, buffer_id: usize) {
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.id == buffer_id)
+        {
+            let buffer = self.buffers.remove(index);
+            self.switch_to_buffer(buffer);
+        }
+    }
+
+    // Area of the screen shared by the windows
+    fn screen_area(&self) -> Rect {
+        let size = self.terminal.size();
+        Rect::new(0, 0, size.width as usize, size.height as usize)
+    }
+
+    fn window_rect(&self) -> Rect {
+        let area = self.screen_area();
+        self.layout.rect(area, self.window_id).unwrap_or(area)
+    }
+
+    // Width and height of the text area of the current window
+    fn view_size(&self) -> (usize, usize) {
+        let rect = self.window_rect();
+        (rect.width, rect.height.saturating_sub(1))
+    }
+
+    fn split_window(&mut self, direction: SplitDirection) -> bool {
+        let rect = self.window_rect();
+        let size = match direction {
+            SplitDirection::Horizontal => rect.height,
+            SplitDirection::Vertical => rect.width,
+        };
+
+        if !self
+            .layout
+            .split(self.window_id, self.next_window_id, direction, size)
+        {
+            self.status_message = StatusMessage::from("Not enough room".to_string());
+            return false;
+        }
+
+        // The new window shows the same buffer and becomes the current one
+        self.windows.push(Window {
+            id: self.window_id,
+            buffer_id: self.buffer_id,
+            cursor_position: self.cursor_position.clone(),
+            offset: self.offset.clone(),
+        });
+        self.window_id = self.next_window_id;
+        self.next_window_id += 1;
+        self.scroll();
+
+        true
+    }
+
+    fn focus_window(&mut self, window_id: usize) {
+        if let Some(index) = self
+            .windows
+            .iter()
+            .position(|window| window.id == window_id)
+        {
+            let window = self.windows.remove(index);
+            self.windows.push(Window {
+                id: self.window_id,
+                buffer_id: self.buffer_id,
+                cursor_position: self.cursor_position.clone(),
+                offset: self.offset.clone(),
+            });
+            self.load_window(window);
+        }
+    }
+
+    fn load_window(&mut self, window: Window) {
+        if window.buffer_id != self.buffer_id {
+            self.show_buffer(window.buffer_id);
+        }
+
+        self.window_id = window.id;
+        self.cursor_position = window.cursor_position;
+        self.offset = window.offset;
+
+        // The buffer may have been edited from another window in the meantime
+        self.cursor_position.y = cmp::min(self.cursor_position.y, self.document.len());
+        self.cursor_position.x = cmp::min(
+            self.cursor_position.x,
+            self.document.row_len(self.cursor_position.y),
+        );
+        self.scroll();
+    }
+
+    // Closes the current window and goes back to the previously used one
+    fn close_window(&mut self) -> bool {
+        if !self.layout.remove(self.window_id) {
+            self.status_message = StatusMessage::from("Cannot close last window".to_string());
+            return false;
+        }
+
+        if let Some(window) = self.windows.pop() {
+            self.load_window(window);
+        }
+        true
+    }
+
+    fn close_other_windows(&mut self) {
+        self.layout = Layout::new(self.window_id);
+        self.windows.clear();
+        self.scroll();
+    }
+
+    fn cycle_window(&mut self, direction: SearchDirection) {
+        let windows = self.layout.windows();
+        let index = windows
+            .iter()
+            .position(|id| *id == self.window_id)
+            .unwrap_or(0);
+        let next = match direction {
+            SearchDirection::Forward => (index + 1) % windows.len(),
+            SearchDirection::Backward => (index + windows.len() - 1) % windows.len(),
+        };
+
+        self.focus_window(windows[next]);
+    }
+
+    fn resize_window(&mut self, direction: SplitDirection, delta: isize) {
+        self.layout.resize(self.window_id, direction, delta);
+        self.scroll();
+    }
+
+    // Ctrl-w commands
+    fn window_command(&mut self) -> Result<(), std::io::Error> {
+        let event = self.read_key()?;
+        let rect = self.window_rect();
+        let cursor = (
+            rect.x + self.cursor_position.x.saturating_sub(self.offset.x),
+            rect.y + self.cursor_position.y.saturating_sub(self.offset.y),
+        );
+
+        let side = match event.code {
+            KeyCode::Char('h') | KeyCode::Left | KeyCode::Backspace => Some(Side::Left),
+            KeyCode::Char('j') | KeyCode::Down => Some(Side::Down),
+            KeyCode::Char('k') | KeyCode::Up => Some(Side::Up),
+            KeyCode::Char('l') | KeyCode::Right => Some(Side::Right),
+            _ => None,
+        };
+        if let Some(side) = side {
+            if let Some(window_id) =
+                self.layout
+                    .neighbour(self.screen_area(), self.window_id, side, cursor)
+            {
+                self.focus_window(window_id);
+            }
+            return Ok(());
+        }
+
+        match event.code {
+            KeyCode::Char('s' | 'S') => {
+                self.split_window(SplitDirection::Horizontal);
+            }
+            KeyCode::Char('v') => {
+                self.split_window(SplitDirection::Vertical);
+            }
+            KeyCode::Char('w') => self.cycle_window(SearchDirection::Forward),
+            KeyCode::Char('W') => self.cycle_window(SearchDirection::Backward),
+            KeyCode::Char('p') => {
+                if let Some(window) = self.windows.last() {
+                    self.focus_window(window.id);
+                }
+            }
+            KeyCode::Char('q') => self.quit_window(),
+            KeyCode::Char('c') => {
+                self.close_window();
+            }
+            KeyCode::Char('o') => self.close_other_windows(),
+            KeyCode::Char('+') => self.resize_window(SplitDirection::Horizontal, 1),
+            KeyCode::Char('-') => self.resize_window(SplitDirection::Horizontal, -1),
+            KeyCode::Char('>') => self.resize_window(SplitDirection::Vertical, 1),
+            KeyCode::Char('<') => self.resize_window(SplitDirection::Vertical, -1),
+            KeyCode::Char('=') => {
+                self.layout.equalize();
+                self.scroll();
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    // Closes the current window, or the editor when it's the last one
+    fn quit_window(&mut self) {
+        if self.windows.is_empty() {
+            self.should_quit = true;
+        } else {
+            self.close_window();
+        }
+    }
+
+    fn check_swap_file(&mut self) {
+        if !self.document.has_swap() {
+            return;
+        }
+
+        loop {
+            self.status_message = StatusMessage::from(
+                "Found a swap file from a previous session: [r]ecover, [d]elete or [i]gnore?"
+                    .to_string(),
+            );
+            if self.refresh_screen().is_err() {
+                return;
+            }
+
+            let event = match self.read_key() {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+
+            match event.code {
+                KeyCode::Char('r') => {
+                    let message = match self.document.recover_swap() {
+                        Ok(()) => "Recovered unsaved changes from the swap file.".to_string(),
+                        Err(e) => format!("ERROR: Could not recover swap file: {e}"),
+                    };
+                    self.status_message = StatusMessage::from(message);
+                    break;
+                }
+                KeyCode::Char('d') => {
+                    self.document.remove_swap();
+                    self.status_message = StatusMessage::from("Swap file deleted.".to_string());
+                    break;
+                }
+                KeyCode::Char('i') | KeyCode::Esc => {
+                    self.document.use_other_swap();
+                    self.status_message = StatusMessage::from(String::new());
+                    break;
+                }
+                _ => (),
             }
         }
     }
 
     fn refresh_screen(&self) -> Result<(), std::io::Error> {
         Terminal::hide_cursor();
-        Terminal::set_cursor_position(&Position::default());
 
         if !self.should_quit {
             // TODO: Don't rerender rows if they haven't changed
-            self.draw_rows();
-            self.draw_status_bar();
+            let (windows, separators) = self.layout.rects(self.screen_area());
+
+            for (window_id, rect) in &windows {
+                self.draw_window(*window_id, rect);
+            }
+            for separator in &separators {
+                self.draw_separator(separator);
+            }
             self.draw_message_bar();
+
+            let rect = self.window_rect();
             Terminal::set_cursor_position(&Position {
-                x: self.cursor_position.x.saturating_sub(self.offset.x),
-                y: self.cursor_position.y.saturating_sub(self.offset.y),
+                x: rect.x + self.cursor_position.x.saturating_sub(self.offset.x),
+                y: rect.y + self.cursor_position.y.saturating_sub(self.offset.y),
             })
         }
 
@@ -126,183 +723,1391 @@ impl Editor {
         Terminal::flush()
     }
 
-    fn save_file(&mut self) {
+    fn save_file(&mut self) -> Result<(), String> {
         if self.document.file_name.is_none() {
-            let new_name = self.prompt("Save as: ", |_, _, _| {}).unwrap_or(None);
+            let new_name = self
+                .prompt("Save as: ", |_, _, _| {})
+                .unwrap_or(None)
+                .filter(|name| !name.is_empty());
 
             if new_name.is_none() {
-                self.status_message = StatusMessage::from("File save aborted.".to_string());
-                return;
+                return Err("File save aborted.".to_string());
             }
             self.document.file_name = new_name;
+            self.update_title();
         }
 
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from("File saved successfully.".to_string());
-        } else {
-            self.status_message = StatusMessage::from("ERROR: Could not save file!".to_string());
+        self.document
+            .save(self.config.editor.backup)
+            .map_err(|_| "ERROR: Could not save file!".to_string())?;
+        self.status_message = StatusMessage::from("File saved successfully.".to_string());
+        Ok(())
+    }
+
+    // :w with a file writes the rows there, or pipes them to a shell command with :w !cmd
+    fn write_rows(
+        &mut self,
+        file: &str,
+        force: bool,
+        first: usize,
+        last: usize,
+    ) -> Result<(), String> {
+        if let Some(shell_command) = file.strip_prefix('!') {
+            return self.pipe_rows(shell_command.trim(), first, last);
+        }
+
+        let whole = first == 0 && last + 1 >= self.document.len();
+        let own_file = file.is_empty() || self.document.file_name.as_deref() == Some(file);
+        if !own_file && !force && Path::new(file).exists() {
+            return Err("E13: File exists (add ! to override)".to_string());
+        }
+
+        if whole && (own_file || self.document.file_name.is_none()) {
+            // A buffer without a name takes the one it is written to
+            if !file.is_empty() {
+                self.document.file_name = Some(file.to_string());
+                self.update_title();
+            }
+            return self.save_file();
+        }
+        if file.is_empty() && self.document.file_name.is_none() {
+            return Err("E32: No file name".to_string());
         }
+        if own_file && !force {
+            return Err("E140: Use ! to write partial buffer".to_string());
+        }
+
+        let file = match self.document.file_name.clone() {
+            Some(name) if file.is_empty() => name,
+            _ => file.to_string(),
+        };
+        self.document
+            .write(&file, first, last, self.config.editor.backup)
+            .map_err(|e| format!("E212: Can't open file for writing: {e}"))?;
+        self.status_message =
+            StatusMessage::from(format!("\"{file}\" {} lines written", last + 1 - first));
+        Ok(())
     }
 
-    // TODO: Refactor this
-    fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let event = Terminal::read_key()?;
-        match self.mode.current_mode {
-            // Normal mode keybindings
-            PossibleModes::Normal => match (event.code, event.modifiers) {
-                (KeyCode::Char(c), KeyModifiers::NONE) => match c {
-                    'h' => {
-                        self.move_cursor(KeyCode::Left);
-                    }
-                    'j' => {
-                        self.move_cursor(KeyCode::Down);
-                    }
-                    'k' => {
-                        self.move_cursor(KeyCode::Up);
-                    }
-                    'l' => {
-                        self.move_cursor(KeyCode::Right);
-                    }
-                    '0' => {
-                        self.move_cursor(KeyCode::Home);
-                    }
-                    '$' => {
-                        self.move_cursor(KeyCode::End);
-                    }
-                    'w' => {
-                        self.move_cursor_word();
-                    }
-                    'b' => {
-                        // self.move_cursor_word_back();
-                    }
-                    'a' => {
-                        self.move_cursor(KeyCode::Right);
-                        self.mode.switch(PossibleModes::Insert);
-                    }
-                    'i' => {
-                        self.mode.switch(PossibleModes::Insert);
-                    }
-                    'v' => {
-                        self.mode.switch(PossibleModes::Visual);
-                    }
-                    'o' => {
-                        self.move_cursor(KeyCode::Down);
-                        self.mode.switch(PossibleModes::Insert);
-                        self.document.insert_newline(&self.cursor_position);
-                    }
-                    ':' => {
-                        self.command_mode();
-                    }
-                    _ => {
-                        self.mode.switch(PossibleModes::OperatorPending);
-                    }
-                },
-                (KeyCode::Char(c), KeyModifiers::CONTROL) => match c {
-                    'd' => {
-                        self.move_cursor(KeyCode::PageDown);
-                    }
-                    'u' => {
-                        self.move_cursor(KeyCode::PageUp);
-                    }
-                    _ => {}
-                },
-                _ => {}
-            },
-            // Insert mode keybindings
-            PossibleModes::Insert => match (event.code, event.modifiers) {
-                (KeyCode::Esc, _) => {
-                    self.mode.switch(PossibleModes::Normal);
-                    self.move_cursor(KeyCode::Left);
-                }
-                (KeyCode::Char(c), KeyModifiers::NONE) => {
-                    self.document.insert(&self.cursor_position, c);
-                    self.move_cursor(KeyCode::Right);
-                }
-                (KeyCode::Char(c), KeyModifiers::SHIFT) => {
-                    self.document.insert(&self.cursor_position, c);
-                    self.move_cursor(KeyCode::Right);
-                }
-                (KeyCode::Enter, _) => {
-                    self.document.insert_newline(&self.cursor_position);
-                    self.move_cursor(KeyCode::Right);
-                }
-                (KeyCode::Delete, _) => {
-                    self.document.delete(&self.cursor_position);
-                }
-                (KeyCode::Backspace, _) => {
-                    if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                        self.move_cursor(KeyCode::Left);
-                        self.document.delete(&self.cursor_position);
-                    }
-                }
-                (KeyCode::Tab, _) => {
-                    for _ in 0..self.config.editor.tab_size {
-                        self.document.insert(&self.cursor_position, ' ');
-                        self.move_cursor(KeyCode::Right);
-                    }
-                }
-                _ => {}
-            },
-            // Visual mode keybindings
-            PossibleModes::Visual => match (event.code, event.modifiers) {
-                (KeyCode::Esc, _) => {
-                    self.mode.switch(PossibleModes::Normal);
-                }
-                _ => {}
-            },
-            // Operator pending mode keybindings
-            PossibleModes::OperatorPending => match (event.code, event.modifiers) {
-                (KeyCode::Esc, _) => {
-                    self.mode.switch(PossibleModes::Normal);
-                }
-                _ => {}
-            },
-            _ => {}
+    fn pipe_rows(&mut self, shell_command: &str, first: usize, last: usize) -> Result<(), String> {
+        if shell_command.is_empty() {
+            return Err("E471: Argument required".to_string());
+        }
+
+        let mut input = self.document.text_range(
+            &Position::new(0, first),
+            &Position::new(self.document.row_len(last), last),
+        );
+        input.push('\n');
+        let (output, status) = utils::pipe_to_command(shell_command, input)
+            .map_err(|e| format!("E282: Cannot run command: {e}"))?;
+
+        let mut lines: Vec<String> = output.lines().map(utils::show_control_chars).collect();
+        if !status.success() {
+            lines.push(match status.code() {
+                Some(code) => format!("shell returned {code}"),
+                None => "shell was killed".to_string(),
+            });
+        }
+        if !lines.is_empty() {
+            if let Err(e) = self.display_list(&lines) {
+                die(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes every modified buffer, even the ones not shown
+    fn write_all(&mut self) -> Result<(), String> {
+        if self.document.is_dirty() {
+            if self.document.file_name.is_none() {
+                return Err(format!("E141: No file name for buffer {}", self.buffer_id));
+            }
+            self.save_file()?;
+        }
+
+        let backup = self.config.editor.backup;
+        for buffer in &mut self.buffers {
+            if !buffer.document.is_dirty() {
+                continue;
+            }
+            if buffer.document.file_name.is_none() {
+                return Err(format!("E141: No file name for buffer {}", buffer.id));
+            }
+            buffer
+                .document
+                .save(backup)
+                .map_err(|_| "ERROR: Could not save file!".to_string())?;
+        }
+
+        self.status_message = StatusMessage::from("All files saved.".to_string());
+        Ok(())
+    }
+
+    // Quitting the editor would lose the changes that haven't been written, unless forced
+    fn check_unsaved(&self, force: bool) -> Result<(), String> {
+        if force {
+            return Ok(());
+        }
+        if self.document.is_dirty() {
+            return Err("E37: No write since last change (add ! to override)".to_string());
+        }
+        match self
+            .buffers
+            .iter()
+            .find(|buffer| buffer.document.is_dirty())
+        {
+            Some(buffer) => Err(format!(
+                "E162: No write since last change for buffer \"{}\"",
+                buffer
+                    .document
+                    .file_name
+                    .clone()
+                    .unwrap_or(String::from("[No Name]"))
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // :q closes the window, and the editor with the last one
+    fn quit(&mut self, force: bool) -> Result<(), String> {
+        if self.windows.is_empty() {
+            self.check_unsaved(force)?;
+        }
+        self.quit_window();
+        Ok(())
+    }
+
+    // Next key to handle, from a macro being replayed or from the terminal
+    fn read_key(&mut self) -> Result<KeyEvent, std::io::Error> {
+        if !self.replay_keys.is_empty() && self.interrupted()? {
+            self.abort_replay();
+            self.status_message = StatusMessage::from("Interrupted".to_string());
+        }
+        if let Some(event) = self.replay_keys.pop_front() {
+            self.replayed_keys += 1;
+            return Ok(event);
+        }
+        self.replayed_keys = 0;
+
+        let event = match self.typeahead.pop_front() {
+            Some(event) => event,
+            None => Terminal::read_key()?,
+        };
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push(event);
+        }
+        Ok(event)
+    }
+
+    // Whether Ctrl-C was pressed, the other keys typed meanwhile are kept for later
+    fn interrupted(&mut self) -> Result<bool, std::io::Error> {
+        while let Some(event) = Terminal::poll_key()? {
+            if event.code == KeyCode::Char('c') && event.modifiers == KeyModifiers::CONTROL {
+                return Ok(true);
+            }
+            self.typeahead.push_back(event);
+        }
+        Ok(false)
+    }
+
+    // Like in Vim, a motion, operator or command that fails stops the macros being replayed
+    fn abort_replay(&mut self) {
+        self.replay_keys.clear();
+    }
+
+    fn start_recording(&mut self, name: char) {
+        if Registers::is_valid(name) && !matches!(name, '-' | '_' | '+' | '*') {
+            self.recording = Some((name, Vec::new()));
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        let Some((name, mut keys)) = self.recording.take() else {
+            return;
+        };
+        // Leave out the q that stopped the recording
+        keys.pop();
+
+        let register = Register {
+            text: keymaps::to_text(&keys),
+            kind: SelectionKind::Charwise,
+        };
+        if let Err(error) = self.registers.record(name, register) {
+            self.status_message = StatusMessage::from(format!("Could not record: {error}"));
+        }
+    }
+
+    fn replay_macro(&mut self, name: char, count: Count) {
+        let name = if name == '@' {
+            match self.last_macro {
+                Some(name) => name,
+                None => {
+                    self.status_message = StatusMessage::from("No previous macro".to_string());
+                    return;
+                }
+            }
+        } else {
+            name
+        };
+        self.last_macro = Some(name);
+
+        let keys = match self.registers.get(Some(name)) {
+            Ok(Some(register)) => keymaps::from_text(&register.text),
+            _ => {
+                self.abort_replay();
+                return;
+            }
+        };
+
+        // A macro calling itself would never end when nothing fails in it
+        let total = self
+            .replayed_keys
+            .saturating_add(self.replay_keys.len())
+            .saturating_add(keys.len().saturating_mul(count.or(1)));
+        if total > MAX_REPLAYED_KEYS {
+            self.abort_replay();
+            self.status_message = StatusMessage::from("Macro too long, stopped".to_string());
+            return;
+        }
+
+        // Keys go first, before what's left of a macro that called this one
+        for _ in 0..count.or(1) {
+            for key in keys.iter().rev() {
+                self.replay_keys.push_front(*key);
+            }
+        }
+    }
+
+    // TODO: Refactor this
+    fn process_keypress(&mut self) -> Result<(), std::io::Error> {
+        let event = self.read_key()?;
+        match self.mode.current_mode {
+            // Normal mode keybindings
+            PossibleModes::Normal | PossibleModes::OperatorPending => {
+                self.normal_keypress(event)?;
+            }
+            // Insert and Replace mode keybindings
+            PossibleModes::Insert | PossibleModes::Replace => {
+                if event.code == KeyCode::Esc {
+                    self.leave_insert_mode();
+                } else {
+                    self.insert_keys.push(event);
+                    self.insert_key(event);
+                }
+            }
+            // Visual mode keybindings
+            PossibleModes::Visual | PossibleModes::VisualLine | PossibleModes::VisualBlock => {
+                self.visual_keypress(event);
+            }
+            _ => {}
+        }
+
+        // Keys that work in both modes
+        match (event.code, event.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::CONTROL) => match c {
+                'q' => {
+                    if self.quit_times > 0 && self.is_any_buffer_dirty() {
+                        self.status_message = StatusMessage::from(format!(
+                                "WARNING: File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                                self.quit_times
+                            ));
+                        self.quit_times -= 1;
+                        return Ok(());
+                    } else {
+                        self.should_quit = true;
+                    }
+                }
+                's' => {
+                    if let Err(message) = self.save_file() {
+                        self.status_message = StatusMessage::from(message);
+                    }
+                }
+                'f' => {
+                    self.search_mode(
+                        "Search (ESC to cancel, arrows to navigate): ",
+                        SearchDirection::Forward,
+                        1,
+                    );
+                }
+                _ => {}
+            },
+            (KeyCode::Up, _)
+            | (KeyCode::Down, _)
+            | (KeyCode::Left, _)
+            | (KeyCode::Right, _)
+            | (KeyCode::PageUp, _)
+            | (KeyCode::PageDown, _)
+            | (KeyCode::Home, _)
+            | (KeyCode::End, _) => self.move_cursor(event.code),
+            _ => (),
+        }
+
+        self.scroll();
+        Ok(())
+    }
+
+    fn normal_keypress(&mut self, event: KeyEvent) -> Result<(), std::io::Error> {
+        if self.recording.is_some()
+            && self.pending_keys.is_empty()
+            && (event.code, event.modifiers) == (KeyCode::Char('q'), KeyModifiers::NONE)
+        {
+            self.stop_recording();
+            return Ok(());
+        }
+
+        self.pending_keys.push(event);
+        let (action, register) = match keymaps::parse(&self.pending_keys, &self.mode.current_mode) {
+            Parsed::Pending => return Ok(()),
+            Parsed::OperatorPending => {
+                self.mode.switch(PossibleModes::OperatorPending);
+                return Ok(());
+            }
+            Parsed::Invalid => (None, None),
+            Parsed::Complete(action, register) => (Some(action), register),
+        };
+
+        self.pending_keys.clear();
+        if self.mode.current_mode == PossibleModes::OperatorPending {
+            self.mode.switch(PossibleModes::Normal);
+        }
+
+        if let Some(action) = action {
+            if action.is_change() {
+                self.pending_change = Some(Change {
+                    action,
+                    register,
+                    inserted: Vec::new(),
+                });
+            }
+            self.execute(action, register)?;
+            self.record_change();
+        }
+
+        Ok(())
+    }
+
+    fn execute(&mut self, action: Action, register: Option<char>) -> Result<(), std::io::Error> {
+        match action {
+            Action::Move(MovementType::Mark { name, linewise }, _, _) => {
+                self.jump_to_mark(name, linewise);
+            }
+            Action::Move(movement, direction, count) => {
+                self.move_cursor_by(movement, direction, count);
+            }
+            Action::Operate(operator, movement, direction, count) => {
+                if let Some(selection) = self.resolve_motion(movement, direction, count).and_then(
+                    |(movement, direction, count)| {
+                        motion::selection(
+                            &self.document,
+                            &self.cursor_position,
+                            operator,
+                            (movement, direction),
+                            count,
+                        )
+                    },
+                ) {
+                    self.apply_operator(operator, selection, register);
+                } else {
+                    self.abort_replay();
+                }
+            }
+            Action::Other(event, count) => self.normal_command(event, count, register)?,
+            Action::WithChar('q', name, _) => self.start_recording(name),
+            Action::WithChar('@', name, count) => self.replay_macro(name, count),
+            Action::WithChar('m', name, _) => self.set_mark(name),
+            Action::WithChar('r', c, count) => self.replace_chars(c, count.or(1)),
+            Action::WithChar(..) => {}
+        }
+
+        Ok(())
+    }
+
+    // Keeps the change that just ended for ., a change that entered Insert mode ends with it
+    fn record_change(&mut self) {
+        if !self.mode.current_mode.is_insert() {
+            if let Some(change) = self.pending_change.take() {
+                self.last_change = Some(change);
+            }
+        }
+    }
+
+    // Replays the last change at the cursor, a count replaces the one it had
+    fn repeat_change(&mut self, count: Count) -> Result<(), std::io::Error> {
+        let Some(change) = self.last_change.clone() else {
+            return Ok(());
+        };
+
+        let action = match count {
+            Count::Default => change.action,
+            count => change.action.with_count(count),
+        };
+        self.pending_change = Some(Change {
+            action,
+            ..change.clone()
+        });
+        self.execute(action, change.register)?;
+
+        if self.mode.current_mode.is_insert() {
+            for key in &change.inserted {
+                self.insert_keys.push(*key);
+                self.insert_key(*key);
+            }
+            self.leave_insert_mode();
+        }
+        self.record_change();
+
+        Ok(())
+    }
+
+    fn normal_command(
+        &mut self,
+        event: KeyEvent,
+        count: Count,
+        register: Option<char>,
+    ) -> Result<(), std::io::Error> {
+        match (event.code, event.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => match c {
+                'a' => {
+                    self.move_cursor(KeyCode::Right);
+                    self.enter_insert_mode();
+                    self.insert_count = count.or(1);
+                }
+                'i' => {
+                    self.enter_insert_mode();
+                    self.insert_count = count.or(1);
+                }
+                'R' => {
+                    self.enter_insert_mode();
+                    self.mode.switch(PossibleModes::Replace);
+                    self.insert_count = count.or(1);
+                }
+                '~' => {
+                    self.toggle_case_forward(count.or(1));
+                }
+                'v' => {
+                    self.start_visual_mode(PossibleModes::Visual);
+                }
+                'V' => {
+                    self.start_visual_mode(PossibleModes::VisualLine);
+                }
+                'o' => {
+                    self.enter_insert_mode();
+                    self.open_line();
+                    self.insert_count = count.or(1);
+                    self.insert_opens_line = true;
+                }
+                'p' => {
+                    self.put(register, false, count.or(1));
+                }
+                'P' => {
+                    self.put(register, true, count.or(1));
+                }
+                'u' => {
+                    for _ in 0..count.or(1) {
+                        if !self.undo() {
+                            break;
+                        }
+                    }
+                }
+                '.' => {
+                    self.repeat_change(count)?;
+                }
+                ':' => {
+                    self.command_mode();
+                }
+                '/' => {
+                    self.search_mode("/", SearchDirection::Forward, count.or(1));
+                }
+                '?' => {
+                    self.search_mode("?", SearchDirection::Backward, count.or(1));
+                }
+                'n' | 'N' => {
+                    let direction = match (c, self.last_search_direction) {
+                        ('n', direction) => direction,
+                        (_, SearchDirection::Forward) => SearchDirection::Backward,
+                        (_, SearchDirection::Backward) => SearchDirection::Forward,
+                    };
+                    if let Err(message) = self.search(direction, count.or(1)) {
+                        self.status_message = StatusMessage::from(message);
+                        self.abort_replay();
+                    }
+                }
+                _ => {}
+            },
+            (KeyCode::Char(c), KeyModifiers::CONTROL) => match c {
+                'd' => {
+                    self.move_cursor(KeyCode::PageDown);
+                }
+                'u' => {
+                    self.move_cursor(KeyCode::PageUp);
+                }
+                'r' => {
+                    for _ in 0..count.or(1) {
+                        if !self.redo() {
+                            break;
+                        }
+                    }
+                }
+                'v' => {
+                    self.start_visual_mode(PossibleModes::VisualBlock);
+                }
+                'w' => {
+                    self.window_command()?;
+                }
+                'o' => {
+                    self.jump_older(count.or(1));
+                }
+                _ => {}
+            },
+            // Terminals send Tab for Ctrl-i
+            (KeyCode::Tab, _) => {
+                self.jump_newer(count.or(1));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn set_mark(&mut self, name: char) {
+        if !Marks::is_valid(name) {
+            self.status_message =
+                StatusMessage::from("E191: Argument must be a letter".to_string());
+            return;
+        }
+
+        // A global mark is in a single file
+        if name.is_ascii_uppercase() {
+            for buffer in &mut self.buffers {
+                buffer.document.remove_mark(name);
+            }
+            self.file_marks.remove(&name);
+        }
+        self.document.set_mark(name, self.cursor_position.clone());
+    }
+
+    // Moves to a mark, global marks can be in another buffer or in a file that was closed
+    fn jump_to_mark(&mut self, name: char, linewise: bool) {
+        let mut from = Some(self.cursor_position.clone());
+
+        if name.is_ascii_uppercase() && self.document.mark(name).is_none() {
+            if let Some(index) = self
+                .buffers
+                .iter()
+                .position(|buffer| buffer.document.mark(name).is_some())
+            {
+                self.push_jump(self.cursor_position.clone());
+                from = None;
+                let buffer = self.buffers.remove(index);
+                self.switch_to_buffer(buffer);
+            } else if let Some((file_name, position)) = self.file_marks.remove(&name) {
+                self.push_jump(self.cursor_position.clone());
+                from = None;
+                self.open_file(&file_name);
+                self.document.set_mark(name, position);
+            }
+        }
+
+        if self.document.mark(name).is_none() {
+            self.status_message = StatusMessage::from("E20: Mark not set".to_string());
+            return;
+        }
+
+        let movement = MovementType::Mark { name, linewise };
+        if let Some(position) = motion::target(
+            &self.document,
+            &self.cursor_position,
+            movement,
+            Direction::Forward,
+            1,
+        ) {
+            // The jump from another buffer was already recorded
+            if let Some(from) = from.filter(|from| *from != position) {
+                self.push_jump(from);
+            }
+            self.cursor_position = position;
+            self.center_if_off_screen();
+        }
+    }
+
+    // Remembers a position before a big jump, for Ctrl-o and the ' mark.
+    // There is one entry per row, the latest one.
+    fn push_jump(&mut self, position: Position) {
+        self.document.set_mark('\'', position.clone());
+
+        let buffer_id = self.buffer_id;
+        self.jumps
+            .retain(|(id, jump)| *id != buffer_id || jump.y != position.y);
+        self.jumps.push((buffer_id, position));
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.jump_index = self.jumps.len();
+    }
+
+    fn jump_older(&mut self, count: usize) {
+        // Coming back from the end of the list returns to where the cursor is now
+        if self.jump_index == self.jumps.len() {
+            self.push_jump(self.cursor_position.clone());
+            self.jump_index = self.jumps.len() - 1;
+        }
+
+        if let Some(index) = self.jump_index.checked_sub(count) {
+            self.go_to_jump(index);
+        }
+    }
+
+    fn jump_newer(&mut self, count: usize) {
+        let index = self.jump_index + count;
+        if index < self.jumps.len() {
+            self.go_to_jump(index);
+        }
+    }
+
+    fn go_to_jump(&mut self, index: usize) {
+        let (buffer_id, position) = self.jumps[index].clone();
+        if buffer_id != self.buffer_id {
+            self.show_buffer(buffer_id);
+        }
+
+        // The text may have changed since
+        let y = cmp::min(position.y, self.document.len().saturating_sub(1));
+        self.cursor_position = Position::new(cmp::min(position.x, self.document.row_len(y)), y);
+        self.jump_index = index;
+        self.center_if_off_screen();
+    }
+
+    fn move_cursor_by(&mut self, movement: MovementType, direction: Direction, count: Count) {
+        let Some((resolved, direction, count)) = self.resolve_motion(movement, direction, count)
+        else {
+            self.abort_replay();
+            return;
+        };
+
+        let target = motion::target(
+            &self.document,
+            &self.cursor_position,
+            resolved,
+            direction,
+            count,
+        );
+        let Some(mut position) = target else {
+            // 0 and $ don't fail when the cursor is already there
+            if resolved != MovementType::LineBoundary {
+                self.abort_replay();
+            }
+            return;
+        };
+
+        // w and W stop on the last character of the document instead of past it, only
+        // operators like dw go to the end
+        if matches!(resolved, MovementType::Word | MovementType::BigWord) {
+            let len = self.document.row_len(position.y);
+            position.x = cmp::min(position.x, len.saturating_sub(1));
+            if position == self.cursor_position {
+                self.abort_replay();
+                return;
+            }
+        }
+
+        if movement.is_jump() {
+            self.push_jump(self.cursor_position.clone());
+            self.cursor_position = position;
+            self.center_if_off_screen();
+        } else {
+            self.cursor_position = position;
+        }
+    }
+
+    // Remembers the last f, F, t or T and turns ; and , into the find they repeat,
+    // turns window lines and percentages into line numbers, and applies the default count
+    fn resolve_motion(
+        &mut self,
+        movement: MovementType,
+        direction: Direction,
+        count: Count,
+    ) -> Option<(MovementType, Direction, usize)> {
+        let (_, height) = self.view_size();
+        let len = self.document.len();
+        let top = self.offset.y + 1;
+        let bottom = cmp::max(top, cmp::min(self.offset.y + height, len));
+
+        let line = match (movement, direction, count) {
+            (MovementType::LineNumber, Direction::Forward, Count::Default) => len,
+            (MovementType::LineNumber, _, count) => count.or(1),
+            (MovementType::ScreenTop, _, count) => cmp::min(top + count.or(1) - 1, bottom),
+            (MovementType::ScreenMiddle, _, _) => (top + bottom) / 2,
+            (MovementType::ScreenBottom, _, count) => {
+                cmp::max(bottom.saturating_sub(count.or(1) - 1), top)
+            }
+            (MovementType::MatchingBracket, _, Count::Number(percent)) => {
+                (cmp::min(percent, 100) * len).div_ceil(100)
+            }
+            _ => return self.resolve_find(movement, direction, count.or(1)),
+        };
+
+        Some((MovementType::LineNumber, Direction::Forward, line))
+    }
+
+    fn resolve_find(
+        &mut self,
+        movement: MovementType,
+        direction: Direction,
+        count: usize,
+    ) -> Option<(MovementType, Direction, usize)> {
+        match movement {
+            MovementType::Find { .. } => {
+                self.last_find = Some((movement, direction));
+                Some((movement, direction, count))
+            }
+            MovementType::RepeatFind => {
+                let (movement, last_direction) = self.last_find?;
+                let movement = match movement {
+                    MovementType::Find { target, till, .. } => MovementType::Find {
+                        target,
+                        till,
+                        repeat: true,
+                    },
+                    movement => movement,
+                };
+                let direction = match direction {
+                    Direction::Backward => last_direction.reversed(),
+                    _ => last_direction,
+                };
+                Some((movement, direction, count))
+            }
+            _ => Some((movement, direction, count)),
+        }
+    }
+
+    // Big jumps show the cursor in the middle of the window when it went off screen
+    fn center_if_off_screen(&mut self) {
+        let (_, height) = self.view_size();
+        let y = self.cursor_position.y;
+        if y < self.offset.y || y >= self.offset.y + height {
+            // Without going past the end of the file
+            let last_top = self.document.len().saturating_sub(height);
+            self.offset.y = cmp::min(y.saturating_sub(height / 2), last_top);
+        }
+    }
+
+    fn insert_key(&mut self, event: KeyEvent) {
+        if self.mode.current_mode == PossibleModes::Replace {
+            self.replace_key(event);
+            return;
+        }
+
+        match (event.code, event.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::NONE) => {
+                self.document.insert(&self.cursor_position, c);
+                self.move_cursor(KeyCode::Right);
+            }
+            (KeyCode::Char(c), KeyModifiers::SHIFT) => {
+                self.document.insert(&self.cursor_position, c);
+                self.move_cursor(KeyCode::Right);
+            }
+            (KeyCode::Enter, _) => {
+                self.document.insert_newline(&self.cursor_position);
+                self.move_cursor(KeyCode::Right);
+            }
+            (KeyCode::Delete, _) => {
+                self.document.delete(&self.cursor_position);
+            }
+            (KeyCode::Backspace, _) if self.cursor_position.x > 0 || self.cursor_position.y > 0 => {
+                self.move_cursor(KeyCode::Left);
+                self.document.delete(&self.cursor_position);
+            }
+            (KeyCode::Tab, _) => {
+                for _ in 0..self.config.editor.tab_size {
+                    self.document.insert(&self.cursor_position, ' ');
+                    self.move_cursor(KeyCode::Right);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Typing overwrites the text under the cursor, Backspace brings it back
+    fn replace_key(&mut self, event: KeyEvent) {
+        let at = self.cursor_position.clone();
+        let row_len = self.document.row_len(at.y);
+
+        match (event.code, event.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                let original = (at.x < row_len).then(|| {
+                    let end = Position::new(at.x + 1, at.y);
+                    self.document.delete_range(&at, &end)
+                });
+                self.document.insert(&at, c);
+                self.replaced.push(original);
+                self.move_cursor(KeyCode::Right);
+            }
+            (KeyCode::Enter, _) => {
+                self.document.insert_newline(&at);
+                self.replaced.push(None);
+                self.move_cursor(KeyCode::Right);
+            }
+            (KeyCode::Backspace, _) => {
+                // Before where Replace mode started, only move the cursor
+                let Some(original) = self.replaced.pop() else {
+                    self.move_cursor(KeyCode::Left);
+                    return;
+                };
+
+                self.move_cursor(KeyCode::Left);
+                self.document.delete(&self.cursor_position);
+                if let Some(original) = original {
+                    self.document.insert_str(&self.cursor_position, &original);
+                }
+            }
+            (KeyCode::Tab, _) => {
+                for _ in 0..self.config.editor.tab_size {
+                    self.replace_key(KeyEvent::new(KeyCode::Char(' '), KeyModifiers::NONE));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // r{char} replaces the characters from the cursor, r<Enter> replaces them with a line break
+    fn replace_chars(&mut self, c: char, count: usize) {
+        let at = self.cursor_position.clone();
+        if at.x + count > self.document.row_len(at.y) {
+            return;
+        }
+
+        let text = if c == '\n' {
+            c.to_string()
+        } else {
+            c.to_string().repeat(count)
+        };
+        self.document.begin_transaction(&at);
+        self.document
+            .delete_range(&at, &Position::new(at.x + count, at.y));
+        let end = self.document.insert_str(&at, &text);
+        self.document.commit_transaction();
+
+        // On the last replaced character, or at the start of the new row
+        self.cursor_position = if c == '\n' {
+            end
+        } else {
+            Position::new(end.x - 1, end.y)
+        };
+    }
+
+    // ~ switches the case of the characters from the cursor and moves after them
+    fn toggle_case_forward(&mut self, count: usize) {
+        let Position { x, y } = self.cursor_position;
+        let row_len = self.document.row_len(y);
+        if x >= row_len {
+            return;
+        }
+
+        let end = cmp::min(x + count, row_len);
+        let selection = Selection {
+            start: Position::new(x, y),
+            end: Position::new(end, y),
+            kind: SelectionKind::Charwise,
+        };
+        self.apply_operator(Operator::ToggleCase, selection, None);
+        self.cursor_position = Position::new(cmp::min(end, row_len - 1), y);
+    }
+
+    fn visual_keypress(&mut self, event: KeyEvent) {
+        self.pending_keys.push(event);
+        let (event, register) = match keymaps::parse(&self.pending_keys, &self.mode.current_mode) {
+            Parsed::Pending | Parsed::OperatorPending => return,
+            Parsed::Complete(Action::Move(movement, direction, count), _) => {
+                self.pending_keys.clear();
+                if let MovementType::TextObject { .. } = movement {
+                    self.select_text_object(movement, count);
+                } else {
+                    self.move_cursor_by(movement, direction, count);
+                }
+                return;
+            }
+            Parsed::Complete(Action::Other(event, _), register) => (event, register),
+            Parsed::Complete(Action::Operate(..) | Action::WithChar(..), _) | Parsed::Invalid => {
+                self.pending_keys.clear();
+                return;
+            }
+        };
+        self.pending_keys.clear();
+
+        let operator = match (event.code, event.modifiers) {
+            (KeyCode::Esc, _) => {
+                self.mode.switch(PossibleModes::Normal);
+                None
+            }
+            (KeyCode::Char('v'), KeyModifiers::CONTROL) => {
+                self.start_visual_mode(PossibleModes::VisualBlock);
+                None
+            }
+            (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
+                self.move_cursor(KeyCode::PageDown);
+                None
+            }
+            (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
+                self.move_cursor(KeyCode::PageUp);
+                None
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => match c {
+                'v' => {
+                    self.start_visual_mode(PossibleModes::Visual);
+                    None
+                }
+                'V' => {
+                    self.start_visual_mode(PossibleModes::VisualLine);
+                    None
+                }
+                'o' => {
+                    mem::swap(&mut self.visual_anchor, &mut self.cursor_position);
+                    None
+                }
+                'd' | 'x' => Some(Operator::Delete),
+                'y' => Some(Operator::Yank),
+                'c' | 's' => Some(Operator::Change),
+                '>' => Some(Operator::Indent),
+                '<' => Some(Operator::Outdent),
+                '~' => Some(Operator::ToggleCase),
+                'u' => Some(Operator::Lowercase),
+                'U' => Some(Operator::Uppercase),
+                'J' => Some(Operator::Join),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if let Some(operator) = operator {
+            if let Some(selection) = self.selection() {
+                self.mode.switch(PossibleModes::Normal);
+                self.apply_operator(operator, selection, register);
+            }
+        }
+    }
+
+    // Selects the text object around the cursor, paragraphs select whole lines
+    fn select_text_object(&mut self, movement: MovementType, count: Count) {
+        let Some(selection) = motion::selection(
+            &self.document,
+            &self.cursor_position,
+            Operator::Yank,
+            (movement, Direction::Forward),
+            count.or(1),
+        ) else {
+            return;
+        };
+
+        if selection.kind == SelectionKind::Linewise {
+            if self.mode.current_mode == PossibleModes::Visual {
+                self.mode.switch(PossibleModes::VisualLine);
+            }
+            self.visual_anchor = selection.start;
+            self.cursor_position = selection.end;
+            return;
+        }
+
+        // The end of a selection is exclusive, the cursor is on its last character
+        let end = match selection.end {
+            _ if selection.end == selection.start => return,
+            Position { x: 0, y } => Position::new(self.document.row_len(y - 1), y - 1),
+            Position { x, y } => Position::new(x - 1, y),
+        };
+        self.visual_anchor = selection.start;
+        self.cursor_position = end;
+    }
+
+    // Starts selecting from the cursor, or leaves visual mode when already in that mode
+    fn start_visual_mode(&mut self, mode: PossibleModes) {
+        if self.mode.current_mode == mode {
+            self.mode.switch(PossibleModes::Normal);
+            return;
+        }
+
+        if !self.mode.current_mode.is_visual() {
+            self.visual_anchor = self.cursor_position.clone();
+        }
+        self.mode.switch(mode);
+    }
+
+    fn selection(&self) -> Option<Selection> {
+        let kind = match self.mode.current_mode {
+            PossibleModes::Visual => SelectionKind::Charwise,
+            PossibleModes::VisualLine => SelectionKind::Linewise,
+            PossibleModes::VisualBlock => SelectionKind::Blockwise,
+            _ => return None,
+        };
+
+        if self.document.is_empty() {
+            return None;
+        }
+
+        Some(Selection::from_visual(
+            &self.visual_anchor,
+            &self.cursor_position,
+            kind,
+            &self.document,
+        ))
+    }
+
+    fn apply_operator(&mut self, operator: Operator, selection: Selection, register: Option<char>) {
+        // The whole operation is undone at once, a change also includes the text typed afterwards
+        self.document.begin_transaction(&self.cursor_position);
+
+        match operator {
+            Operator::Delete => {
+                self.store(register, &selection, true);
+                self.cursor_position = self.delete_selection(&selection);
+            }
+            Operator::Yank => {
+                self.store(register, &selection, false);
+                // Yanking lines only moves the cursor when they start above it
+                if selection.kind != SelectionKind::Linewise
+                    || self.cursor_position.y != selection.first_row()
+                {
+                    self.cursor_position = selection.start.clone();
+                }
+
+                let lines = selection.last_row() - selection.first_row() + 1;
+                if lines > 2 {
+                    let message = match selection.kind {
+                        SelectionKind::Blockwise => format!("block of {lines} lines yanked"),
+                        _ => format!("{lines} lines yanked"),
+                    };
+                    self.status_message = StatusMessage::from(message);
+                }
+            }
+            Operator::Change => {
+                self.store(register, &selection, true);
+
+                if selection.kind == SelectionKind::Linewise {
+                    // Keep an empty row to type in
+                    let (start, end) = &selection.ranges(&self.document)[0];
+                    self.document.delete_range(start, end);
+                    self.cursor_position = start.clone();
+                } else {
+                    self.cursor_position = self.delete_selection(&selection);
+                }
+
+                self.enter_insert_mode();
+                return;
+            }
+            Operator::Indent | Operator::Outdent => {
+                for y in selection.first_row()..=selection.last_row() {
+                    self.indent_row(y, operator == Operator::Indent);
+                }
+                self.cursor_position = self.first_non_blank(selection.first_row());
+            }
+            Operator::ToggleCase | Operator::Lowercase | Operator::Uppercase => {
+                for (start, end) in selection.ranges(&self.document) {
+                    let text = self.document.text_range(&start, &end);
+                    let converted = match operator {
+                        Operator::Lowercase => text.to_lowercase(),
+                        Operator::Uppercase => text.to_uppercase(),
+                        _ => utils::toggle_case(&text),
+                    };
+
+                    if converted != text {
+                        self.document.delete_range(&start, &end);
+                        self.document.insert_str(&start, &converted);
+                    }
+                }
+                self.cursor_position = selection.start.clone();
+            }
+            Operator::Join => {
+                let first = selection.first_row();
+                let last = cmp::max(selection.last_row(), first + 1);
+                self.join_rows(first, last - first);
+            }
+        }
+
+        self.document.commit_transaction();
+    }
+
+    // Copies the selected text into a register, before it is deleted or after it is yanked
+    fn store(&mut self, name: Option<char>, selection: &Selection, delete: bool) {
+        let register = Register {
+            text: selection.text(&self.document),
+            kind: selection.kind,
+        };
+
+        let result = if delete {
+            self.registers.delete(name, register)
+        } else {
+            self.registers.yank(name, register)
+        };
+
+        if let Err(error) = result {
+            self.status_message = StatusMessage::from(format!("Could not copy: {error}"));
+        }
+    }
+
+    // Puts the content of a register after the cursor, or before it
+    fn put(&mut self, name: Option<char>, before: bool, count: usize) {
+        let register = match self.registers.get(name) {
+            Ok(Some(register)) => register,
+            Ok(None) => {
+                self.status_message =
+                    StatusMessage::from(format!("Nothing in register {}", name.unwrap_or('"')));
+                return;
+            }
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("Could not paste: {error}"));
+                return;
+            }
+        };
+
+        // With a count the text is put that many times, side by side for blocks
+        let register = Register {
+            text: match register.kind {
+                SelectionKind::Blockwise => register
+                    .text
+                    .split('\n')
+                    .map(|line| line.repeat(count))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                _ => register.text.repeat(count),
+            },
+            kind: register.kind,
+        };
+
+        let y = cmp::min(
+            self.cursor_position.y,
+            self.document.len().saturating_sub(1),
+        );
+        let width = self.document.row_len(y);
+        // Text goes after the character under the cursor with p
+        let x = if before || width == 0 {
+            cmp::min(self.cursor_position.x, width)
+        } else {
+            cmp::min(self.cursor_position.x + 1, width)
+        };
+
+        self.document.begin_transaction(&self.cursor_position);
+
+        match register.kind {
+            SelectionKind::Linewise => {
+                let text = register.text.strip_suffix('\n').unwrap_or(&register.text);
+                let row = if before {
+                    self.document
+                        .insert_str(&Position::new(0, y), &format!("{text}\n"));
+                    y
+                } else {
+                    self.document
+                        .insert_str(&Position::new(width, y), &format!("\n{text}"));
+                    y + 1
+                };
+                self.cursor_position = self.first_non_blank(row);
+            }
+            SelectionKind::Charwise => {
+                let at = Position::new(x, y);
+                let end = self.document.insert_str(&at, &register.text);
+
+                // The cursor ends on the last character put, or on the first one for several lines
+                self.cursor_position = if register.text.contains('\n') {
+                    at
+                } else {
+                    Position::new(end.x.saturating_sub(1), end.y)
+                };
+            }
+            SelectionKind::Blockwise => {
+                for (index, line) in register.text.split('\n').enumerate() {
+                    let row = y + index;
+                    let len = self.document.len();
+                    if row > 0 && row >= len {
+                        let last = Position::new(self.document.row_len(len - 1), len - 1);
+                        self.document.insert_str(&last, "\n");
+                    }
+
+                    // Short rows are padded up to the block
+                    let width = self.document.row_len(row);
+                    if width < x {
+                        self.document
+                            .insert_str(&Position::new(width, row), &" ".repeat(x - width));
+                    }
+                    self.document.insert_str(&Position::new(x, row), line);
+                }
+                self.cursor_position = Position::new(x, y);
+            }
+        }
+
+        self.document.commit_transaction();
+    }
+
+    // Deletes the selected text and returns where the cursor should go
+    fn delete_selection(&mut self, selection: &Selection) -> Position {
+        match selection.kind {
+            SelectionKind::Charwise | SelectionKind::Blockwise => {
+                for (start, end) in selection.ranges(&self.document) {
+                    self.document.delete_range(&start, &end);
+                }
+                selection.start.clone()
+            }
+            SelectionKind::Linewise => {
+                let (first, last) = (selection.first_row(), selection.last_row());
+                let len = self.document.len();
+
+                // Take the line break after the rows, or the one before them for the last rows
+                let (start, end) = if last + 1 < len {
+                    (Position::new(0, first), Position::new(0, last + 1))
+                } else if first > 0 {
+                    (
+                        Position::new(self.document.row_len(first - 1), first - 1),
+                        Position::new(self.document.row_len(last), last),
+                    )
+                } else {
+                    (
+                        Position::new(0, first),
+                        Position::new(self.document.row_len(last), last),
+                    )
+                };
+                self.document.delete_range(&start, &end);
+
+                let y = cmp::min(first, self.document.len().saturating_sub(1));
+                self.first_non_blank(y)
+            }
+        }
+    }
+
+    fn first_non_blank(&self, y: usize) -> Position {
+        let x = self
+            .document
+            .row(y)
+            .map(|row| {
+                row.as_str()
+                    .graphemes(true)
+                    .take_while(|grapheme| grapheme.trim().is_empty())
+                    .count()
+            })
+            .unwrap_or(0);
+
+        Position::new(x, y)
+    }
+
+    fn indent_row(&mut self, y: usize, indent: bool) {
+        let indent_size = self.config.editor.indent_size as usize;
+
+        if indent {
+            if self.document.row_len(y) > 0 {
+                self.document
+                    .insert_str(&Position::new(0, y), &" ".repeat(indent_size));
+            }
+            return;
+        }
+
+        let text = self
+            .document
+            .row(y)
+            .map(|row| row.as_str().to_string())
+            .unwrap_or_default();
+        let width = if text.starts_with('\t') {
+            1
+        } else {
+            text.chars()
+                .take(indent_size)
+                .take_while(|c| *c == ' ')
+                .count()
+        };
+        self.document
+            .delete_range(&Position::new(0, y), &Position::new(width, y));
+    }
+
+    // Joins `count` rows after the given one to it, separated by a single space
+    fn join_rows(&mut self, y: usize, count: usize) {
+        for _ in 0..count {
+            if y + 1 >= self.document.len() {
+                break;
+            }
+
+            let width = self.document.row_len(y);
+            let next = self.first_non_blank(y + 1);
+            self.document.delete_range(&Position::new(width, y), &next);
+
+            let ends_with_space = self
+                .document
+                .row(y)
+                .is_some_and(|row| row.as_str().ends_with(' '));
+            let joined_text = self.document.row_len(y) > width;
+            if width > 0 && !ends_with_space && joined_text {
+                self.document.insert_str(&Position::new(width, y), " ");
+            }
+
+            self.cursor_position = Position::new(width, y);
         }
+    }
 
-        // Keys that work in both modes
-        match (event.code, event.modifiers) {
-            (KeyCode::Char(c), KeyModifiers::CONTROL) => match c {
-                'q' => {
-                    if self.quit_times > 0 && self.document.is_dirty() {
-                        self.status_message = StatusMessage::from(format!(
-                                "WARNING: File has unsaved changes. Press Ctrl-Q {} more times to quit.",
-                                self.quit_times
-                            ));
-                        self.quit_times -= 1;
-                        return Ok(());
-                    } else {
-                        self.should_quit = true;
-                    }
-                }
-                's' => {
-                    self.save_file();
-                }
-                'f' => {
-                    self.search_mode();
-                }
-                _ => {}
-            },
-            (KeyCode::Up, _)
-            | (KeyCode::Down, _)
-            | (KeyCode::Left, _)
-            | (KeyCode::Right, _)
-            | (KeyCode::PageUp, _)
-            | (KeyCode::PageDown, _)
-            | (KeyCode::Home, _)
-            | (KeyCode::End, _) => self.move_cursor(event.code),
-            _ => (),
+    fn enter_insert_mode(&mut self) {
+        // Everything typed until Esc is undone in a single step
+        self.document.begin_transaction(&self.cursor_position);
+        self.mode.switch(PossibleModes::Insert);
+        self.insert_count = 1;
+        self.insert_opens_line = false;
+    }
+
+    fn leave_insert_mode(&mut self) {
+        // A count typed before i, a or o inserts the text that many times
+        let keys = mem::take(&mut self.insert_keys);
+        if let Some(change) = &mut self.pending_change {
+            change.inserted = keys.clone();
+        }
+        for _ in 1..self.insert_count {
+            if self.insert_opens_line {
+                self.open_line();
+            }
+            for key in &keys {
+                self.insert_key(*key);
+            }
         }
 
-        self.scroll();
-        Ok(())
+        self.document.commit_transaction();
+        self.replaced.clear();
+        self.mode.switch(PossibleModes::Normal);
+        self.move_cursor(KeyCode::Left);
+        self.record_change();
+    }
+
+    // Starts a new row below the cursor
+    fn open_line(&mut self) {
+        self.move_cursor(KeyCode::End);
+        self.document.insert_newline(&self.cursor_position);
+        self.cursor_position = Position::new(0, self.cursor_position.y + 1);
+    }
+
+    // Returns false when there was nothing left to undo
+    fn undo(&mut self) -> bool {
+        match self.document.undo() {
+            Some(position) => {
+                self.cursor_position = position;
+                self.status_message = StatusMessage::from(String::new());
+                true
+            }
+            None => {
+                self.status_message = StatusMessage::from("Already at oldest change".to_string());
+                false
+            }
+        }
+    }
+
+    // Returns false when there was nothing left to redo
+    fn redo(&mut self) -> bool {
+        match self.document.redo() {
+            Some(position) => {
+                self.cursor_position = position;
+                self.status_message = StatusMessage::from(String::new());
+                true
+            }
+            None => {
+                self.status_message = StatusMessage::from("Already at newest change".to_string());
+                false
+            }
+        }
     }
 
     fn scroll(&mut self) {
         let Position { x, y } = self.cursor_position;
-        let width = self.terminal.size().width as usize;
-        let height = self.terminal.size().height as usize;
-        let mut offset = &mut self.offset;
+        let (width, height) = self.view_size();
+        let offset = &mut self.offset;
 
         if y < offset.y {
             offset.y = y;
@@ -318,14 +2123,10 @@ impl Editor {
     }
 
     fn move_cursor(&mut self, key: KeyCode) {
-        let terminal_window_height = self.terminal.size().height as usize;
+        let (_, terminal_window_height) = self.view_size();
         let Position { mut x, mut y } = self.cursor_position;
         let document_height = self.document.len();
-        let width = if let Some(row) = self.document.row(y) {
-            row.len()
-        } else {
-            0
-        };
+        let width = self.document.row_len(y);
 
         // Don't allow cursor to go past the end of the line
         if x > width {
@@ -354,34 +2155,24 @@ impl Editor {
 
             // Arrow key movements
             KeyCode::Up => y = y.saturating_sub(1),
-            KeyCode::Down => {
-                if y < document_height {
-                    y = y.saturating_add(1);
-                }
+            KeyCode::Down if y < document_height => {
+                y = y.saturating_add(1);
             }
             KeyCode::Left => match x.cmp(&0) {
                 cmp::Ordering::Greater => x -= 1, // Move one to the left
-                cmp::Ordering::Equal => {
-                    // Move to the end of the previous line if cursor is at the start of the line
-                    if y > 0 {
-                        y -= 1;
-                        if let Some(row) = self.document.row(y) {
-                            x = row.len();
-                        } else {
-                            x = 0;
-                        }
-                    }
+                // Move to the end of the previous line if cursor is at the start of the line
+                cmp::Ordering::Equal if y > 0 => {
+                    y -= 1;
+                    x = self.document.row_len(y);
                 }
                 _ => (),
             },
             KeyCode::Right => match x.cmp(&width) {
                 cmp::Ordering::Less => x += 1, // Move one to the right
-                cmp::Ordering::Equal => {
-                    // Move to the start of the next line if cursor is at the end of the line
-                    if y < document_height {
-                        y += 1;
-                        x = 0;
-                    }
+                // Move to the start of the next line if cursor is at the end of the line
+                cmp::Ordering::Equal if y < document_height => {
+                    y += 1;
+                    x = 0;
                 }
                 _ => (),
             },
@@ -391,29 +2182,30 @@ impl Editor {
         self.cursor_position = Position { x, y };
     }
 
-    fn move_cursor_word(&mut self) {}
-
-    fn draw_welcome_message(&self) {
+    fn draw_welcome_message(&self, width: usize) {
         let mut welcome_message = format!("Miv editor -- version {}", EDITOR_VERSION);
         let message_len = welcome_message.len();
 
-        let width = self.terminal.size().width as usize;
         let padding = width.saturating_sub(message_len) / 2;
         let spaces = " ".repeat(padding.saturating_sub(1));
 
         welcome_message = format!("~{}{}", spaces, welcome_message);
-        welcome_message.truncate(width);
-
-        println!("{}\r", welcome_message);
+        print!("{}", utils::pad(&welcome_message, width));
     }
 
-    fn draw_row(&self, row: &Row, line_number: u16) {
+    fn draw_row(
+        &self,
+        row: &Row,
+        offset: usize,
+        width: usize,
+        highlight: Option<Range<usize>>,
+        matches: &[Range<usize>],
+    ) {
         // TODO: Add line numbers
         // TODO: Cache the syntax highlighting
-        let width = self.terminal.size().width as usize;
-        let start = self.offset.x;
-        let end = self.offset.x.saturating_add(width);
-        let row = row.get_display_graphemes(start, end);
+        let start = offset;
+        let end = offset.saturating_add(width);
+        let row = utils::show_control_chars(&row.get_display_graphemes(start, end));
 
         // TODO: cache the syntax highlighting
         let syntax = self
@@ -427,71 +2219,200 @@ impl Editor {
             &self.highlighting.theme_set.themes[&self.config.appearance.theme],
         );
 
-        let ranges: Vec<(Style, &str)> = h.highlight(&row, &self.highlighting.syntax_set);
-        let escaped = as_24_bit_terminal_escaped(&ranges[..], false);
-        println!("{escaped}\r");
+        let ranges: Vec<(Style, &str)> = h
+            .highlight_line(&row, &self.highlighting.syntax_set)
+            .unwrap_or_default();
+        let len = row.graphemes(true).count();
+        let highlight = highlight.map(|range| {
+            range.start.saturating_sub(offset)..cmp::min(range.end.saturating_sub(offset), width)
+        });
+
+        let mut escaped = String::new();
+        if highlight.is_some() || !matches.is_empty() {
+            // Escape every grapheme on its own to reverse the selected ones and color the matches
+            let graphemes = ranges
+                .iter()
+                .flat_map(|(style, text)| text.graphemes(true).map(move |g| (*style, g)));
+
+            for (column, (style, grapheme)) in graphemes.enumerate() {
+                if let Some(highlight) = &highlight {
+                    if column == highlight.start {
+                        escaped.push_str(&Attribute::Reverse.to_string());
+                    }
+                    if column == highlight.end {
+                        escaped.push_str(&Attribute::NoReverse.to_string());
+                    }
+                }
+
+                let text = as_24_bit_terminal_escaped(&[(style, grapheme)], false);
+                if matches
+                    .iter()
+                    .any(|range| range.contains(&(column + offset)))
+                {
+                    escaped.push_str(&text.on(Color::DarkYellow).to_string());
+                } else {
+                    escaped.push_str(&text);
+                }
+            }
+        } else {
+            escaped = as_24_bit_terminal_escaped(&ranges[..], false);
+        }
+        escaped.push_str(&Attribute::Reset.to_string());
+
+        // Highlighted line break
+        let mut len = len;
+        if let Some(highlight) = highlight {
+            if highlight.end > len && len < width {
+                escaped.push_str(&" ".reverse().to_string());
+                len += 1;
+            }
+        }
+
+        let padding = " ".repeat(width.saturating_sub(len));
+        print!("{escaped}{padding}");
     }
 
-    fn draw_rows(&self) {
-        let height = self.terminal.size().height;
+    fn draw_window(&self, window_id: usize, rect: &Rect) {
+        let view = if window_id == self.window_id {
+            Some((&self.document, &self.cursor_position, &self.offset))
+        } else {
+            self.windows
+                .iter()
+                .find(|window| window.id == window_id)
+                .and_then(|window| {
+                    self.buffer_document(window.buffer_id)
+                        .map(|document| (document, &window.cursor_position, &window.offset))
+                })
+        };
 
-        for terminal_row in 0..height {
-            Terminal::clear_current_line();
+        // Only the current window shows the selection
+        let selection = if window_id == self.window_id {
+            self.selection().or_else(|| self.highlighted_match.clone())
+        } else {
+            None
+        };
 
-            if let Some(row) = self.document.row(terminal_row as usize + self.offset.y) {
-                self.draw_row(row, terminal_row);
-            } else if self.document.is_empty() && terminal_row == height / 3 {
-                self.draw_welcome_message();
+        if let Some((document, cursor_position, offset)) = view {
+            self.draw_rows(
+                document,
+                offset,
+                rect,
+                selection.as_ref(),
+                self.search_highlight.as_ref(),
+            );
+            self.draw_status_bar(document, cursor_position, rect, window_id == self.window_id);
+        }
+    }
+
+    fn draw_rows(
+        &self,
+        document: &Document,
+        offset: &Position,
+        rect: &Rect,
+        selection: Option<&Selection>,
+        search: Option<&Regex>,
+    ) {
+        let height = rect.height.saturating_sub(1);
+
+        for terminal_row in 0..height {
+            Terminal::set_cursor_position(&Position::new(rect.x, rect.y + terminal_row));
+
+            let y = terminal_row + offset.y;
+            if let Some(row) = document.row(y) {
+                let highlight = selection.and_then(|selection| selection.columns(y, row.len()));
+                let matches = search.map(|regex| row.matches(regex)).unwrap_or_default();
+                self.draw_row(&row, offset.x, rect.width, highlight, &matches);
+            } else if document.is_empty() && terminal_row == height / 3 {
+                self.draw_welcome_message(rect.width);
             } else {
-                println!("~\r");
+                print!("{}", utils::pad("~", rect.width));
             }
         }
     }
 
-    fn draw_status_bar(&self) {
-        let width = self.terminal.size().width as usize;
+    fn draw_separator(&self, rect: &Rect) {
+        for y in rect.y..rect.y + rect.height {
+            Terminal::set_cursor_position(&Position::new(rect.x, y));
+            print!("{}", "│".black().on_grey());
+        }
+    }
 
-        let modified_indicator = if self.document.is_dirty() {
+    fn draw_status_bar(
+        &self,
+        document: &Document,
+        cursor_position: &Position,
+        rect: &Rect,
+        active: bool,
+    ) {
+        let width = rect.width;
+
+        let modified_indicator = if document.is_dirty() {
             "(modified)"
         } else {
             ""
         };
 
-        let mut file_name = "[No Name]".to_string();
-        if let Some(name) = &self.document.file_name {
-            file_name = name.clone();
-            file_name.truncate(width);
-        }
+        let file_name = document
+            .file_name
+            .clone()
+            .unwrap_or("[No Name]".to_string());
 
         let app_name = " Miv ";
-        let left_info = format!(
-            " {} {} [{}]",
-            file_name, modified_indicator, self.mode.current_mode
-        );
+        let recording = match &self.recording {
+            Some((name, _)) => format!(" recording @{name}"),
+            None => String::new(),
+        };
+        let left_info = if active {
+            format!(
+                " {} {} [{}]{}",
+                file_name, modified_indicator, self.mode.current_mode, recording
+            )
+        } else {
+            format!(" {} {}", file_name, modified_indicator)
+        };
 
         let left_content = format!("{}{}", app_name, left_info);
+        // Keys typed so far for a command, like a count
+        let pending = if active && !self.pending_keys.is_empty() {
+            format!("{}   ", keymaps::display(&self.pending_keys))
+        } else {
+            String::new()
+        };
         let right_content = format!(
-            "Filetype: {} | Line {}/{}",
-            self.document.file_type(),
-            self.cursor_position.y.saturating_add(1),
-            self.document.len()
+            "{}Filetype: {} | {} | Line {}/{}",
+            pending,
+            document.file_type(),
+            document.file_format(),
+            cursor_position.y.saturating_add(1),
+            document.len()
         );
 
         let len = left_content.len() + right_content.len();
         let empty_space: String = " ".repeat(width.saturating_sub(len));
-        let status_bar_content = format!("{}{}{}", left_info, empty_space, right_content);
+        let status_bar_content = utils::pad(
+            &format!("{}{}{}", left_info, empty_space, right_content),
+            width.saturating_sub(app_name.len()),
+        );
+
+        let color = if active {
+            self.mode.current_mode.to_color()
+        } else {
+            Color::DarkGrey
+        };
 
-        println!(
-            "{}{}\r",
-            app_name
+        Terminal::set_cursor_position(&Position::new(rect.x, rect.y + rect.height - 1));
+        print!(
+            "{}{}",
+            utils::pad(app_name, cmp::min(width, app_name.len()))
                 .bold()
                 .white()
-                .on(self.mode.current_mode.to_color()),
+                .on(color),
             status_bar_content.black().on_grey()
         );
     }
 
     fn draw_message_bar(&self) {
+        Terminal::set_cursor_position(&Position::new(0, self.terminal.size().height as usize));
         Terminal::clear_current_line();
 
         let message = &self.status_message;
@@ -508,26 +2429,25 @@ impl Editor {
         C: FnMut(&mut Self, KeyCode, &String),
     {
         let mut result = String::new();
+        let mut cancelled = false;
 
         loop {
             self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
             self.refresh_screen()?;
 
-            let event = Terminal::read_key()?;
+            let event = self.read_key()?;
 
             match (event.code, event.modifiers) {
                 (KeyCode::Backspace, _) => {
-                    result.truncate(result.len().saturating_sub(1));
-                    if !result.is_empty() {
-                        result.pop();
-                    }
+                    result.pop();
                 }
-                (KeyCode::Char(c), KeyModifiers::NONE) => {
+                (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
                     result.push(c);
                 }
                 (KeyCode::Enter, _) => break,
                 (KeyCode::Esc, _) => {
                     result.truncate(0);
+                    cancelled = true;
                     break;
                 }
                 _ => (),
@@ -538,7 +2458,8 @@ impl Editor {
 
         self.status_message = StatusMessage::from(String::new());
 
-        if result.is_empty() {
+        // Esc cancels, Enter gives what was typed even when it's nothing
+        if cancelled {
             Ok(None)
         } else {
             Ok(Some(result))
@@ -547,69 +2468,561 @@ impl Editor {
 
     fn command_mode(&mut self) {
         let old_position = self.cursor_position.clone();
-        let query = self.prompt(":", |editor, key, query| {}).unwrap_or(None);
+        let query = self
+            .prompt(":", |_, _, _| {})
+            .unwrap_or(None)
+            .filter(|command| !command.is_empty());
 
         match query {
-            Some(command) => match command.as_str() {
-                "q" | "quit" => {
-                    self.should_quit = true;
+            Some(command) => self.run_command(&command),
+            None => {
+                self.cursor_position = old_position;
+                self.scroll();
+            }
+        }
+    }
+
+    fn run_command(&mut self, input: &str) {
+        if let Err(message) = self.execute_command(input) {
+            self.status_message = StatusMessage::from(message);
+            self.abort_replay();
+        }
+    }
+
+    // Parses a command line and runs it, the error is shown in the status bar
+    fn execute_command(&mut self, input: &str) -> Result<(), String> {
+        let Some(command) = command::parse(input, &self.commands)? else {
+            return Ok(());
+        };
+        let (first, last) = command.rows(&self.document, self.cursor_position.y, |pattern| {
+            self.ignore_case(pattern)
+        })?;
+        let argument = command.argument.as_str();
+
+        match command.kind {
+            CommandKind::Goto => {
+                self.move_cursor_by(
+                    MovementType::LineNumber,
+                    Direction::Forward,
+                    Count::Number(last + 1),
+                );
+            }
+            CommandKind::Quit => {
+                self.quit(command.bang)?;
+            }
+            CommandKind::QuitAll => {
+                self.check_unsaved(command.bang)?;
+                self.should_quit = true;
+            }
+            CommandKind::Write => {
+                self.write_rows(argument, command.bang, first, last)?;
+            }
+            CommandKind::WriteAll => {
+                self.write_all()?;
+            }
+            CommandKind::WriteQuit => {
+                self.write_rows(argument, command.bang, first, last)?;
+                self.quit(command.bang)?;
+            }
+            CommandKind::WriteQuitAll => {
+                self.write_all()?;
+                self.check_unsaved(command.bang)?;
+                self.should_quit = true;
+            }
+            CommandKind::Exit => {
+                if self.document.is_dirty() || !argument.is_empty() {
+                    self.write_rows(argument, command.bang, first, last)?;
+                }
+                self.quit(command.bang)?;
+            }
+            CommandKind::SaveAs => {
+                let own_file = self.document.file_name.as_deref() == Some(argument);
+                if !command.bang && !own_file && Path::new(argument).exists() {
+                    return Err("E13: File exists (add ! to override)".to_string());
+                }
+                self.document
+                    .save_as(argument, self.config.editor.backup)
+                    .map_err(|e| format!("E212: Can't open file for writing: {e}"))?;
+                self.update_title();
+                self.status_message = StatusMessage::from("File saved successfully.".to_string());
+            }
+            CommandKind::Split | CommandKind::VerticalSplit => {
+                let direction = match command.kind {
+                    CommandKind::Split => SplitDirection::Horizontal,
+                    _ => SplitDirection::Vertical,
+                };
+                if self.split_window(direction) && !argument.is_empty() {
+                    self.open_file(argument);
+                }
+            }
+            CommandKind::Close => {
+                self.close_window();
+            }
+            CommandKind::Only => {
+                self.close_other_windows();
+            }
+            CommandKind::Set => {
+                self.set_option(argument);
+            }
+            CommandKind::Edit => {
+                self.open_file(argument);
+            }
+            CommandKind::NextBuffer => {
+                self.cycle_buffer(SearchDirection::Forward);
+            }
+            CommandKind::PreviousBuffer => {
+                self.cycle_buffer(SearchDirection::Backward);
+            }
+            CommandKind::Buffers => {
+                if let Err(e) = self.list_buffers() {
+                    die(e);
+                }
+            }
+            CommandKind::DeleteBuffer => {
+                self.delete_buffer(command.bang);
+            }
+            CommandKind::Substitute => {
+                self.substitute(argument, first, last)?;
+            }
+            CommandKind::Global | CommandKind::VGlobal => {
+                let invert = command.kind == CommandKind::VGlobal || command.bang;
+                self.global(argument, first, last, invert)?;
+            }
+            CommandKind::NoHighlight => {
+                self.search_highlight = None;
+            }
+            CommandKind::Delete => {
+                let (register, count) = command::register_and_count(argument)?;
+
+                // With a count, that many lines from the last line of the range
+                let (first, last) = match count {
+                    Some(count) => {
+                        let end = last.saturating_add(count.saturating_sub(1));
+                        (last, cmp::min(end, self.document.len().saturating_sub(1)))
+                    }
+                    None => (first, last),
+                };
+                let selection = Selection {
+                    start: Position::new(0, first),
+                    end: Position::new(0, last),
+                    kind: SelectionKind::Linewise,
+                };
+                self.apply_operator(Operator::Delete, selection, register);
+            }
+        }
+
+        Ok(())
+    }
+
+    // :s on the rows from first to last, undone as a single change
+    fn substitute(&mut self, argument: &str, first: usize, last: usize) -> Result<(), String> {
+        let substitute = match (argument.is_empty(), &self.last_substitute) {
+            // :s alone repeats the last substitution, without its flags
+            (true, Some(previous)) => Substitute {
+                flags: Flags::default(),
+                ..previous.clone()
+            },
+            (true, None) => return Err("E35: No previous regular expression".to_string()),
+            (false, _) => {
+                let mut substitute = Substitute::parse(argument)?;
+                if substitute.pattern.is_empty() {
+                    substitute.pattern = self
+                        .last_pattern
+                        .clone()
+                        .ok_or_else(|| "E35: No previous regular expression".to_string())?;
+                }
+                substitute
+            }
+        };
+        let flags = substitute.flags.clone();
+        let ignore_case = flags
+            .ignore_case
+            .unwrap_or(self.ignore_case(&substitute.pattern));
+        let regex = pattern::compile(&substitute.pattern, ignore_case)?;
+        self.last_substitute = Some(substitute.clone());
+        self.last_pattern = Some(substitute.pattern.clone());
+
+        let cursor = self.cursor_position.clone();
+        let mut confirm = flags.confirm && !flags.count_only;
+        let mut found = false;
+        let mut quit = false;
+        let (mut substitutions, mut lines) = (0, 0);
+        let mut last_line = None;
+        let (mut y, mut last) = (first, last);
+
+        self.document.begin_transaction(&cursor);
+        while y <= last && !quit {
+            // The row as it was before its matches were replaced
+            let Some(row) = self.document.row(y) else {
+                break;
+            };
+            // Where the text after the handled matches is now, replacements may break the row
+            let mut anchor = Position::new(0, y);
+            // Column of the row after the handled matches
+            let mut consumed = 0;
+            let mut changed = false;
+
+            for captures in regex.captures_iter(row.as_str()) {
+                let Some(matched) = captures.get(0) else {
+                    continue;
+                };
+                // A match starting or ending inside a grapheme takes all of it
+                let start_x = row.containing_column(matched.start());
+                let end_x = row.column(matched.end());
+                if start_x < consumed {
+                    continue;
+                }
+                let start = Position::new(anchor.x + start_x - consumed, anchor.y);
+                let end = Position::new(start.x + end_x - start_x, start.y);
+                consumed = end_x;
+                anchor = end.clone();
+                found = true;
+
+                let mut replace = !flags.count_only;
+                if confirm {
+                    match self
+                        .confirm_substitution(&substitute.replacement, &start, &end)
+                        .unwrap_or('q')
+                    {
+                        'n' => replace = false,
+                        'a' => confirm = false,
+                        'l' => quit = true,
+                        'q' => {
+                            quit = true;
+                            break;
+                        }
+                        _ => (),
+                    }
+                }
+
+                if replace {
+                    self.document.delete_range(&start, &end);
+                    anchor = self
+                        .document
+                        .insert_str(&start, &substitute.expand(&captures));
+                }
+                if replace || flags.count_only {
+                    substitutions += 1;
+                    changed = true;
+                }
+                if quit || !flags.global {
+                    break;
+                }
+            }
+
+            if changed {
+                lines += 1;
+                last_line = Some(anchor.y);
+            }
+            last += anchor.y - y;
+            y = anchor.y + 1;
+        }
+        self.document.commit_transaction();
+        self.highlighted_match = None;
+
+        if !found {
+            self.cursor_position = cursor;
+            return Err(format!("E486: Pattern not found: {}", substitute.pattern));
+        }
+
+        self.cursor_position = match last_line {
+            Some(y) if !flags.count_only => self.first_non_blank(y),
+            _ => cursor,
+        };
+        let noun = match (flags.count_only, substitutions) {
+            (true, 1) => "match",
+            (true, _) => "matches",
+            (false, 1) => "substitution",
+            (false, _) => "substitutions",
+        };
+        let line_noun = if lines == 1 { "line" } else { "lines" };
+        self.status_message =
+            StatusMessage::from(format!("{substitutions} {noun} on {lines} {line_noun}"));
+        Ok(())
+    }
+
+    // :g runs a command on each row from first to last that matches a pattern, :v on the others.
+    // The rows are found first, and followed as the command adds or removes lines.
+    fn global(
+        &mut self,
+        argument: &str,
+        first: usize,
+        last: usize,
+        invert: bool,
+    ) -> Result<(), String> {
+        let mut chars = argument.chars();
+        let delimiter = pattern::delimiter(&mut chars)?;
+        let mut pattern = pattern::delimited(&mut chars, delimiter);
+        if pattern.is_empty() {
+            pattern = self
+                .last_pattern
+                .clone()
+                .ok_or_else(|| "E35: No previous regular expression".to_string())?;
+        }
+        let input = chars.as_str().trim().to_string();
+        let regex = pattern::compile(&pattern, self.ignore_case(&pattern))?;
+        self.last_pattern = Some(pattern.clone());
+
+        if let Some(command) = command::parse(&input, &self.commands)? {
+            if matches!(command.kind, CommandKind::Global | CommandKind::VGlobal) {
+                return Err("E147: Cannot do :global recursive".to_string());
+            }
+        }
+
+        let rows: Vec<usize> = (first..=last)
+            .filter(|&y| {
+                self.document
+                    .row(y)
+                    .is_some_and(|row| regex.is_match(row.as_str()) != invert)
+            })
+            .collect();
+        if rows.is_empty() {
+            return Err(if invert {
+                format!("Pattern found in every line: {pattern}")
+            } else {
+                format!("E486: Pattern not found: {pattern}")
+            });
+        }
+
+        // Without a command, the rows are printed
+        if input.is_empty() {
+            let lines: Vec<String> = rows
+                .iter()
+                .filter_map(|&y| self.document.row(y))
+                .map(|row| row.as_str().to_string())
+                .collect();
+            if let Err(e) = self.display_list(&lines) {
+                die(e);
+            }
+            return Ok(());
+        }
+
+        // Everything the commands change is undone at once
+        let steps = self.document.undo_steps();
+        let mut error = None;
+        let mut not_found = None;
+        let mut succeeded = false;
+        self.document.mark_lines(&rows);
+        while let Some(y) = self.document.next_marked_line() {
+            self.cursor_position = Position::new(0, y);
+            match self.execute_command(&input) {
+                Ok(()) => succeeded = true,
+                // A pattern of the command not being on every row is expected
+                Err(message) if message.starts_with("E486") => {
+                    not_found.get_or_insert(message);
                 }
-                "w" | "save" => {
-                    self.save_file();
+                Err(message) => {
+                    error.get_or_insert(message);
                 }
-                _ => {
+            }
+        }
+        self.document.clear_marked_lines();
+        self.document.merge_undo_steps(steps);
+
+        match (error, not_found) {
+            (Some(message), _) => Err(message),
+            (None, Some(message)) if !succeeded => Err(message),
+            _ => Ok(()),
+        }
+    }
+
+    // Shows a match of :s with the c flag and waits for y, n, a, q or l
+    fn confirm_substitution(
+        &mut self,
+        replacement: &str,
+        start: &Position,
+        end: &Position,
+    ) -> Result<char, std::io::Error> {
+        // An empty match still highlights the character it is on
+        let end = if end == start {
+            Position::new(end.x + 1, end.y)
+        } else {
+            end.clone()
+        };
+        self.highlighted_match = Some(Selection {
+            start: start.clone(),
+            end,
+            kind: SelectionKind::Charwise,
+        });
+        self.cursor_position = start.clone();
+        self.scroll();
+        self.status_message =
+            StatusMessage::from(format!("replace with {replacement} (y/n/a/q/l)?"));
+
+        loop {
+            self.refresh_screen()?;
+
+            match self.read_key()?.code {
+                KeyCode::Char(c @ ('y' | 'n' | 'a' | 'q' | 'l')) => return Ok(c),
+                KeyCode::Esc => return Ok('q'),
+                _ => (),
+            }
+        }
+    }
+
+    fn set_option(&mut self, option: &str) {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option.trim_end_matches('?'), None),
+        };
+
+        match (name, value) {
+            ("fileformat" | "ff", None) => {
+                self.status_message = StatusMessage::from(format!(
+                    "fileformat={}",
+                    self.document.file_format().line_ending
+                ));
+            }
+            ("fileformat" | "ff", Some(value)) => match LineEnding::from_name(value) {
+                Some(line_ending) => self.document.set_line_ending(line_ending),
+                None => {
                     self.status_message =
-                        StatusMessage::from("Not an editor command: ".to_string() + &command)
+                        StatusMessage::from(format!("Invalid argument: {option}"));
                 }
             },
-            None => {
-                self.cursor_position = old_position;
-                self.scroll();
+            _ => self.set_flag(option, name, value.is_some()),
+        }
+    }
+
+    // Boolean options are set by their name, reset with "no" in front of it and shown with "?"
+    fn set_flag(&mut self, option: &str, name: &str, has_value: bool) {
+        let (flag_name, enable) = match name.strip_prefix("no") {
+            Some(flag_name) => (flag_name, false),
+            None => (name, true),
+        };
+        let editor = &mut self.config.editor;
+        let (full_name, flag) = match flag_name {
+            "ignorecase" | "ic" => ("ignorecase", &mut editor.ignore_case),
+            "smartcase" | "scs" => ("smartcase", &mut editor.smart_case),
+            _ => {
+                self.status_message = StatusMessage::from(format!("Unknown option: {name}"));
+                return;
             }
+        };
+
+        if has_value {
+            self.status_message = StatusMessage::from(format!("Invalid argument: {option}"));
+        } else if option.ends_with('?') {
+            let prefix = if *flag { "" } else { "no" };
+            self.status_message = StatusMessage::from(format!("{prefix}{full_name}"));
+        } else {
+            *flag = enable;
         }
     }
 
-    fn search_mode(&mut self) {
+    // Searches for a pattern as it is typed, highlighting the matches. Arrows go to the next or
+    // previous match, Enter goes to the count-th one from where the search started.
+    fn search_mode(&mut self, prompt: &str, direction: SearchDirection, count: usize) {
         let old_position = self.cursor_position.clone();
-        let mut direction = SearchDirection::Forward;
+        let old_highlight = self.search_highlight.take();
+        let mut direction = direction;
 
         let query = self
-            .prompt(
-                "Search (ESC to cancel, arrows to navigate): ",
-                |editor, key, query| {
-                    let mut moved = false;
-
-                    match key {
-                        KeyCode::Right | KeyCode::Down => {
-                            direction = SearchDirection::Forward;
-                            editor.move_cursor(KeyCode::Right);
-                            moved = true;
-                        }
-                        KeyCode::Left | KeyCode::Up => direction = SearchDirection::Backward,
-                        _ => (),
+            .prompt(prompt, |editor, key, query| {
+                let from = match key {
+                    KeyCode::Right | KeyCode::Down => {
+                        direction = SearchDirection::Forward;
+                        editor.cursor_position.clone()
                     }
-
-                    if let Some(position) =
-                        editor
-                            .document
-                            .find(query, &editor.cursor_position, direction)
-                    {
-                        editor.cursor_position = position;
-                        editor.scroll();
-                    } else if moved {
-                        editor.move_cursor(KeyCode::Left);
+                    KeyCode::Left | KeyCode::Up => {
+                        direction = SearchDirection::Backward;
+                        editor.cursor_position.clone()
                     }
+                    _ => old_position.clone(),
+                };
 
-                    // editor.document.highlight(Some(query))
-                },
-            )
+                // An incomplete pattern may not compile yet
+                editor.search_highlight = Some(query)
+                    .filter(|query| !query.is_empty())
+                    .and_then(|query| pattern::compile(query, editor.ignore_case(query)).ok());
+                editor.cursor_position = editor
+                    .search_highlight
+                    .as_ref()
+                    .and_then(|regex| editor.find_match(regex, &from, direction))
+                    .unwrap_or(old_position.clone());
+                editor.scroll();
+            })
             .unwrap_or(None);
 
-        if query.is_none() {
-            self.cursor_position = old_position;
-            self.scroll();
+        self.cursor_position = old_position;
+        match query {
+            Some(query) => {
+                // An empty pattern searches for the last one again
+                if !query.is_empty() {
+                    self.last_pattern = Some(query);
+                }
+                self.last_search_direction = direction;
+                if let Err(message) = self.search(direction, count) {
+                    self.status_message = StatusMessage::from(message);
+                    self.abort_replay();
+                }
+            }
+            None => self.search_highlight = old_highlight,
+        }
+        self.scroll();
+    }
+
+    // Goes to the count-th match of the last pattern and highlights all of them
+    fn search(&mut self, direction: SearchDirection, count: usize) -> Result<(), String> {
+        let pattern = self
+            .last_pattern
+            .clone()
+            .ok_or_else(|| "E35: No previous regular expression".to_string())?;
+        let regex = pattern::compile(&pattern, self.ignore_case(&pattern))?;
+
+        let mut position = self.cursor_position.clone();
+        let mut wrapped = false;
+        for _ in 0..count {
+            let next = self
+                .find_match(&regex, &position, direction)
+                .ok_or_else(|| format!("E486: Pattern not found: {pattern}"))?;
+            wrapped |= match direction {
+                SearchDirection::Forward => next <= position,
+                SearchDirection::Backward => next >= position,
+            };
+            position = next;
+        }
+        self.search_highlight = Some(regex);
+
+        self.push_jump(self.cursor_position.clone());
+        self.cursor_position = position;
+        self.center_if_off_screen();
+
+        let message = match (wrapped, direction) {
+            (true, SearchDirection::Forward) => "search hit BOTTOM, continuing at TOP".to_string(),
+            (true, SearchDirection::Backward) => "search hit TOP, continuing at BOTTOM".to_string(),
+            (false, SearchDirection::Forward) => format!("/{pattern}"),
+            (false, SearchDirection::Backward) => format!("?{pattern}"),
+        };
+        self.status_message = StatusMessage::from(message);
+        Ok(())
+    }
+
+    // The match after a position, or before it, wrapping around the document
+    fn find_match(
+        &self,
+        regex: &Regex,
+        from: &Position,
+        direction: SearchDirection,
+    ) -> Option<Position> {
+        let last = self.document.len().checked_sub(1)?;
+
+        match direction {
+            SearchDirection::Forward => self
+                .document
+                .find(regex, &Position::new(from.x + 1, from.y), direction)
+                .or_else(|| self.document.find(regex, &Position::default(), direction)),
+            SearchDirection::Backward => self.document.find(regex, from, direction).or_else(|| {
+                self.document
+                    .find(regex, &Position::new(usize::MAX, last), direction)
+            }),
         }
-        // self.document.highlight(None);
+    }
+
+    // With smartcase, a pattern with an uppercase letter doesn't ignore case
+    fn ignore_case(&self, pattern: &str) -> bool {
+        let editor = &self.config.editor;
+        editor.ignore_case && !(editor.smart_case && pattern::has_uppercase(pattern))
     }
 }
 
@@ -0,0 +1,103 @@
+use std::fmt::Display;
+
+const BOM: &str = "\u{feff}";
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    #[default]
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(LineEnding::Unix),
+            "dos" => Some(LineEnding::Dos),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+}
+
+impl Display for LineEnding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// How a file was laid out on disk, so it can be written back the same way
+#[derive(Clone, Copy)]
+pub struct FileFormat {
+    pub line_ending: LineEnding,
+    pub final_newline: bool,
+    pub bom: bool,
+}
+
+impl Default for FileFormat {
+    fn default() -> Self {
+        Self {
+            line_ending: LineEnding::Unix,
+            final_newline: true,
+            bom: false,
+        }
+    }
+}
+
+impl FileFormat {
+    // Detects the format of the file contents and returns it with the lines, stripped of their endings
+    pub fn detect(contents: &str) -> (Self, Vec<&str>) {
+        let (bom, contents) = match contents.strip_prefix(BOM) {
+            Some(rest) => (true, rest),
+            None => (false, contents),
+        };
+
+        // Use whichever line ending is the most common
+        let dos_endings = contents.matches("\r\n").count();
+        let unix_endings = contents.matches('\n').count() - dos_endings;
+        let line_ending = if dos_endings > unix_endings {
+            LineEnding::Dos
+        } else {
+            LineEnding::Unix
+        };
+
+        let format = Self {
+            line_ending,
+            final_newline: contents.is_empty() || contents.ends_with('\n'),
+            bom,
+        };
+
+        (format, contents.lines().collect())
+    }
+
+    pub fn bom(&self) -> &'static str {
+        if self.bom {
+            BOM
+        } else {
+            ""
+        }
+    }
+}
+
+impl Display for FileFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.line_ending)?;
+        if self.bom {
+            write!(f, " [BOM]")?;
+        }
+        if !self.final_newline {
+            write!(f, " [noeol]")?;
+        }
+        Ok(())
+    }
+}
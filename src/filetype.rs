@@ -16,7 +16,7 @@ impl FileType {
     }
 
     pub fn from(file_name: &str) -> Self {
-        let extension = file_name.split('.').last().unwrap_or("");
+        let extension = file_name.split('.').next_back().unwrap_or("");
         let name = match extension {
             "rs" => "Rust",
             "toml" => "TOML",
@@ -0,0 +1,244 @@
+use crate::Position;
+use unicode_segmentation::UnicodeSegmentation;
+
+// A single primitive edit on a document. Text may span several rows, separated by '\n'.
+#[derive(Clone)]
+pub enum Change {
+    Insert { at: Position, text: String },
+    Delete { at: Position, text: String },
+}
+
+impl Change {
+    pub fn inverse(&self) -> Self {
+        match self {
+            Change::Insert { at, text } => Change::Delete {
+                at: at.clone(),
+                text: text.clone(),
+            },
+            Change::Delete { at, text } => Change::Insert {
+                at: at.clone(),
+                text: text.clone(),
+            },
+        }
+    }
+
+    pub fn position(&self) -> &Position {
+        match self {
+            Change::Insert { at, .. } | Change::Delete { at, .. } => at,
+        }
+    }
+}
+
+// Position right after `text` once it has been inserted at `at`
+pub fn end_position(at: &Position, text: &str) -> Position {
+    let mut position = at.clone();
+
+    for (index, line) in text.split('\n').enumerate() {
+        let width = line.graphemes(true).count();
+        if index == 0 {
+            position.x += width;
+        } else {
+            position.y += 1;
+            position.x = width;
+        }
+    }
+
+    position
+}
+
+// A group of changes that is undone and redone as a single step
+pub struct Transaction {
+    id: usize,
+    changes: Vec<Change>,
+    cursor: Position,
+}
+
+impl Transaction {
+    fn new(id: usize, cursor: &Position) -> Self {
+        Self {
+            id,
+            changes: Vec::new(),
+            cursor: cursor.clone(),
+        }
+    }
+
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    // Where the cursor was before the transaction started
+    pub fn cursor(&self) -> &Position {
+        &self.cursor
+    }
+
+    fn push(&mut self, change: Change) {
+        // Merge consecutive typed characters into one insertion
+        if let (
+            Some(Change::Insert { at, text }),
+            Change::Insert {
+                at: new_at,
+                text: new_text,
+            },
+        ) = (self.changes.last_mut(), &change)
+        {
+            if end_position(at, text) == *new_at {
+                text.push_str(new_text);
+                return;
+            }
+        }
+
+        self.changes.push(change);
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    undo_stack: Vec<Transaction>,
+    redo_stack: Vec<Transaction>,
+    current: Option<Transaction>,
+    last_id: usize,
+}
+
+impl History {
+    // Start grouping changes, does nothing if a transaction is already open
+    pub fn begin(&mut self, cursor: &Position) {
+        if self.current.is_none() {
+            self.current = Some(self.transaction(cursor));
+        }
+    }
+
+    fn transaction(&mut self, cursor: &Position) -> Transaction {
+        self.last_id += 1;
+        Transaction::new(self.last_id, cursor)
+    }
+
+    pub fn commit(&mut self) {
+        if let Some(transaction) = self.current.take() {
+            if !transaction.changes.is_empty() {
+                self.undo_stack.push(transaction);
+            }
+        }
+    }
+
+    pub fn record(&mut self, change: Change) {
+        self.redo_stack.clear();
+
+        match &mut self.current {
+            Some(transaction) => transaction.push(change),
+            None => {
+                // Changes made outside of a transaction are undone on their own
+                let mut transaction = self.transaction(change.position());
+                transaction.push(change);
+                self.undo_stack.push(transaction);
+            }
+        }
+    }
+
+    // Identifies the text the history has led to, 0 being the text it started from
+    pub fn state(&self) -> usize {
+        match &self.current {
+            Some(transaction) if !transaction.changes.is_empty() => transaction.id,
+            _ => self
+                .undo_stack
+                .last()
+                .map_or(0, |transaction| transaction.id),
+        }
+    }
+
+    // Number of steps that can be undone
+    pub fn len(&self) -> usize {
+        self.undo_stack.len()
+    }
+
+    // Merges the steps made since there were `len` of them into a single one
+    pub fn merge_since(&mut self, len: usize) {
+        self.commit();
+        if self.undo_stack.len() <= len + 1 {
+            return;
+        }
+
+        let mut transactions = self.undo_stack.split_off(len).into_iter();
+        if let Some(mut merged) = transactions.next() {
+            for transaction in transactions {
+                merged.id = transaction.id;
+                merged.changes.extend(transaction.changes);
+            }
+            self.undo_stack.push(merged);
+        }
+    }
+
+    pub fn undo(&mut self) -> Option<&Transaction> {
+        self.commit();
+
+        let transaction = self.undo_stack.pop()?;
+        self.redo_stack.push(transaction);
+        self.redo_stack.last()
+    }
+
+    pub fn redo(&mut self) -> Option<&Transaction> {
+        self.commit();
+
+        let transaction = self.redo_stack.pop()?;
+        self.undo_stack.push(transaction);
+        self.undo_stack.last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(x: usize, text: &str) -> Change {
+        Change::Insert {
+            at: Position::new(x, 0),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn undoing_every_change_returns_to_the_first_state() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.record(insert(1, "b"));
+        assert_ne!(history.state(), 0);
+
+        history.undo();
+        history.undo();
+        assert_eq!(history.state(), 0);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn redo_returns_to_the_same_state() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        let state = history.state();
+
+        history.undo();
+        history.redo();
+        assert_eq!(history.state(), state);
+    }
+
+    #[test]
+    fn a_new_change_after_undo_is_a_new_state() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        let state = history.state();
+
+        history.undo();
+        history.record(insert(0, "b"));
+        assert_ne!(history.state(), state);
+        assert_ne!(history.state(), 0);
+    }
+
+    #[test]
+    fn committing_an_open_transaction_keeps_its_state() {
+        let mut history = History::default();
+        history.begin(&Position::new(0, 0));
+        history.record(insert(0, "a"));
+        let state = history.state();
+
+        history.commit();
+        assert_eq!(history.state(), state);
+    }
+}
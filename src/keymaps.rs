@@ -1,73 +1,575 @@
-use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
-use crate::{Mode, PossibleModes};
+use crate::operator::Operator;
+use crate::registers::Registers;
+use crate::PossibleModes;
 
-enum Command {
-    Save,
-    Quit,
-}
-
-enum Direction {
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
     Forward,
     Backward,
     Down,
     Up,
 }
 
-enum MovementType {
+impl Direction {
+    pub fn reversed(self) -> Self {
+        match self {
+            Direction::Forward => Direction::Backward,
+            Direction::Backward => Direction::Forward,
+            Direction::Down => Direction::Up,
+            Direction::Up => Direction::Down,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MovementType {
     Word,
+    // Non-blank characters, including punctuation
+    BigWord,
+    WordEnd,
+    BigWordEnd,
     Line,
     Character,
+    // Start or end of the line
+    LineBoundary,
+    // The cursor line and the ones below it, for doubled operators like dd
+    CurrentLine,
+    // The line of the count, gg defaults to the first one and G to the last one
+    LineNumber,
+    // H, M and L go to the top, middle or bottom line of the window
+    ScreenTop,
+    ScreenMiddle,
+    ScreenBottom,
+    // The bracket matching the one under or after the cursor, {count}% goes to a percentage of the file
+    MatchingBracket,
+    // { and } go to the empty line before or after the paragraph
+    Paragraph,
+    // f and F go to a character of the line, t and T stop next to it.
+    // A repeated t or T skips the character it is already next to.
+    Find {
+        target: char,
+        till: bool,
+        repeat: bool,
+    },
+    // ; and , repeat the last find, the same way or the other way
+    RepeatFind,
+    // ' goes to the first non-blank of the row of a mark, ` to the mark itself
+    Mark {
+        name: char,
+        linewise: bool,
+    },
+    // Text objects like iw and a(, they select text around the cursor instead of moving it
+    TextObject {
+        object: TextObject,
+        inner: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextObject {
+    Word,
+    BigWord,
+    Sentence,
+    Paragraph,
+    Quote(char),
+    // The opening and closing brackets
+    Bracket(char, char),
+    // XML or HTML tags
+    Tag,
+}
+
+impl MovementType {
+    // Motions that are remembered in the jump list
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            MovementType::LineNumber
+                | MovementType::ScreenTop
+                | MovementType::ScreenMiddle
+                | MovementType::ScreenBottom
+                | MovementType::MatchingBracket
+                | MovementType::Paragraph
+                | MovementType::Mark { .. }
+        )
+    }
 }
 
-enum Count {
-    Number(u32),
-    Infinity,
+#[derive(Clone, Copy, PartialEq)]
+pub enum Count {
+    // No count was typed, the command uses its default
+    Default,
+    Number(usize),
 }
 
+impl Count {
+    pub fn or(self, default: usize) -> usize {
+        match self {
+            Count::Default => default,
+            Count::Number(count) => count,
+        }
+    }
+
+    // Counts on an operator and on its motion multiply, 2d3w deletes six words
+    fn times(self, other: Count) -> Count {
+        match (self, other) {
+            (Count::Default, count) | (count, Count::Default) => count,
+            (Count::Number(a), Count::Number(b)) => Count::Number(a.saturating_mul(b)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum Action {
     Move(MovementType, Direction, Count),
-    Delete,
-    Yank,
-    Change,
-}
-
-pub fn convert_keypress_to_action(event: KeyEvent, current_mode: &Mode) -> Option<Action> {
-    // match current_mode {
-    //     // Normal mode
-    //     //
-    //     // Insert mode
-
-    //     // Visual mode
-
-    //     // Operator mode
-    //     _ => None,
-    // };
-    let mut action: Action = Action::Move(
-        MovementType::Character,
-        Direction::Forward,
-        Count::Number(0),
-    );
-
-    // Keys that work in both modes
-    match (event.code, event.modifiers) {
-        (KeyCode::Up, _) => {
-            action = Action::Move(MovementType::Character, Direction::Up, Count::Number(1));
+    Operate(Operator, MovementType, Direction, Count),
+    // Any other key, with the count typed before it
+    Other(KeyEvent, Count),
+    // A key taking the character typed after it, like q{register}
+    WithChar(char, char, Count),
+}
+
+impl Action {
+    // Whether the action changes the text, so . can repeat it
+    pub fn is_change(&self) -> bool {
+        match self {
+            Action::Move(..) => false,
+            Action::Operate(operator, ..) => *operator != Operator::Yank,
+            Action::Other(key, _) => {
+                matches!(
+                    character(key),
+                    Some('i' | 'a' | 'o' | 'p' | 'P' | 'R' | '~')
+                )
+            }
+            Action::WithChar(key, ..) => *key == 'r',
         }
-        // (KeyCode::Up, _)
-        // | (KeyCode::Down, _)
-        // | (KeyCode::Left, _)
-        // | (KeyCode::Right, _)
-        // | (KeyCode::PageUp, _)
-        // | (KeyCode::PageDown, _)
-        // | (KeyCode::Home, _)
-        // | (KeyCode::End, _) => Some(Action::Move(
-        //     MovementType::Character,
-        //     Direction::Forward,
-        //     Count::Number(1),
-        // )),
-        _ => {}
-    }
-
-    Some(action)
+    }
+
+    pub fn with_count(self, count: Count) -> Self {
+        match self {
+            Action::Move(movement, direction, _) => Action::Move(movement, direction, count),
+            Action::Operate(operator, movement, direction, _) => {
+                Action::Operate(operator, movement, direction, count)
+            }
+            Action::Other(key, _) => Action::Other(key, count),
+            Action::WithChar(key, argument, _) => Action::WithChar(key, argument, count),
+        }
+    }
+}
+
+// Motions and operators, the same keys as Vim
+const MOTIONS: &[(&str, (MovementType, Direction))] = &[
+    ("h", (MovementType::Character, Direction::Backward)),
+    ("l", (MovementType::Character, Direction::Forward)),
+    ("j", (MovementType::Line, Direction::Down)),
+    ("k", (MovementType::Line, Direction::Up)),
+    ("0", (MovementType::LineBoundary, Direction::Backward)),
+    ("$", (MovementType::LineBoundary, Direction::Forward)),
+    ("w", (MovementType::Word, Direction::Forward)),
+    ("b", (MovementType::Word, Direction::Backward)),
+    ("e", (MovementType::WordEnd, Direction::Forward)),
+    ("ge", (MovementType::WordEnd, Direction::Backward)),
+    ("W", (MovementType::BigWord, Direction::Forward)),
+    ("B", (MovementType::BigWord, Direction::Backward)),
+    ("E", (MovementType::BigWordEnd, Direction::Forward)),
+    ("gE", (MovementType::BigWordEnd, Direction::Backward)),
+    ("gg", (MovementType::LineNumber, Direction::Backward)),
+    ("G", (MovementType::LineNumber, Direction::Forward)),
+    ("H", (MovementType::ScreenTop, Direction::Down)),
+    ("M", (MovementType::ScreenMiddle, Direction::Down)),
+    ("L", (MovementType::ScreenBottom, Direction::Up)),
+    ("%", (MovementType::MatchingBracket, Direction::Forward)),
+    ("{", (MovementType::Paragraph, Direction::Backward)),
+    ("}", (MovementType::Paragraph, Direction::Forward)),
+    (";", (MovementType::RepeatFind, Direction::Forward)),
+    (",", (MovementType::RepeatFind, Direction::Backward)),
+];
+
+const OPERATORS: &[(&str, Operator)] = &[
+    ("d", Operator::Delete),
+    ("y", Operator::Yank),
+    ("c", Operator::Change),
+    (">", Operator::Indent),
+    ("<", Operator::Outdent),
+    ("g~", Operator::ToggleCase),
+    ("gu", Operator::Lowercase),
+    ("gU", Operator::Uppercase),
+];
+
+// Text objects, after i (inner) or a (around)
+const TEXT_OBJECTS: &[(&str, TextObject)] = &[
+    ("w", TextObject::Word),
+    ("W", TextObject::BigWord),
+    ("s", TextObject::Sentence),
+    ("p", TextObject::Paragraph),
+    ("\"", TextObject::Quote('"')),
+    ("'", TextObject::Quote('\'')),
+    ("`", TextObject::Quote('`')),
+    ("(", TextObject::Bracket('(', ')')),
+    (")", TextObject::Bracket('(', ')')),
+    ("b", TextObject::Bracket('(', ')')),
+    ("[", TextObject::Bracket('[', ']')),
+    ("]", TextObject::Bracket('[', ']')),
+    ("{", TextObject::Bracket('{', '}')),
+    ("}", TextObject::Bracket('{', '}')),
+    ("B", TextObject::Bracket('{', '}')),
+    ("<", TextObject::Bracket('<', '>')),
+    (">", TextObject::Bracket('<', '>')),
+    ("t", TextObject::Tag),
+];
+
+// Normal mode keys followed by a character
+const TAKE_CHAR: &[char] = &['q', '@', 'm', 'r'];
+
+// Special keys in the text of a macro, as Vim stores them, or from a private use area
+const SPECIAL_KEYS: &[(KeyCode, char)] = &[
+    (KeyCode::Esc, '\x1b'),
+    (KeyCode::Enter, '\r'),
+    (KeyCode::Tab, '\t'),
+    (KeyCode::Backspace, '\x08'),
+    (KeyCode::Delete, '\x7f'),
+    (KeyCode::Left, '\u{e000}'),
+    (KeyCode::Right, '\u{e001}'),
+    (KeyCode::Up, '\u{e002}'),
+    (KeyCode::Down, '\u{e003}'),
+    (KeyCode::Home, '\u{e004}'),
+    (KeyCode::End, '\u{e005}'),
+    (KeyCode::PageUp, '\u{e006}'),
+    (KeyCode::PageDown, '\u{e007}'),
+];
+
+// Normal mode shortcuts for an operator and a motion
+const SHORTCUTS: &[(&str, (Operator, MovementType, Direction))] = &[
+    (
+        "x",
+        (
+            Operator::Delete,
+            MovementType::Character,
+            Direction::Forward,
+        ),
+    ),
+    (
+        "X",
+        (
+            Operator::Delete,
+            MovementType::Character,
+            Direction::Backward,
+        ),
+    ),
+    (
+        "D",
+        (
+            Operator::Delete,
+            MovementType::LineBoundary,
+            Direction::Forward,
+        ),
+    ),
+    (
+        "C",
+        (
+            Operator::Change,
+            MovementType::LineBoundary,
+            Direction::Forward,
+        ),
+    ),
+    (
+        "s",
+        (
+            Operator::Change,
+            MovementType::Character,
+            Direction::Forward,
+        ),
+    ),
+    (
+        "S",
+        (Operator::Change, MovementType::CurrentLine, Direction::Down),
+    ),
+    (
+        "Y",
+        (Operator::Yank, MovementType::CurrentLine, Direction::Down),
+    ),
+    (
+        "J",
+        (Operator::Join, MovementType::CurrentLine, Direction::Down),
+    ),
+];
+
+enum Lookup<T> {
+    // The binding and how many keys it took
+    Found(T, usize),
+    // The keys start a binding
+    Incomplete,
+    NotFound,
+}
+
+pub enum Parsed {
+    // The action, and the register it reads or writes
+    Complete(Action, Option<char>),
+    // A count was typed, waiting for the command
+    Pending,
+    // An operator was typed, waiting for its motion
+    OperatorPending,
+    Invalid,
+}
+
+// Parses the keys typed so far in Normal or Visual mode: [count] motion,
+// [count] operator [count] motion or text object, [count] operator operator, or [count] key,
+// any of them optionally preceded by a register ("x).
+// Operators apply to the selection in Visual mode, so they are plain keys there.
+pub fn parse(keys: &[KeyEvent], current_mode: &PossibleModes) -> Parsed {
+    let (count, keys) = parse_count(keys);
+    let (register, keys) = match keys {
+        [quote, rest @ ..] if character(quote) == Some('"') => match rest.first() {
+            None => return Parsed::Pending,
+            Some(name) => match character(name).filter(|c| Registers::is_valid(*c)) {
+                Some(name) => (Some(name), &rest[1..]),
+                None => return Parsed::Invalid,
+            },
+        },
+        _ => (None, keys),
+    };
+    let (register_count, keys) = parse_count(keys);
+    let count = count.times(register_count);
+
+    let Some(key) = keys.first() else {
+        return Parsed::Pending;
+    };
+
+    let found = motion(keys);
+    if let Lookup::Found((movement, direction), _) = found {
+        return Parsed::Complete(Action::Move(movement, direction, count), register);
+    }
+
+    if let Some(command) = character(key).filter(|c| TAKE_CHAR.contains(c)) {
+        if current_mode.is_visual() {
+            return Parsed::Invalid;
+        }
+
+        // Enter and Tab are characters too, r<Enter> breaks the line
+        let argument = |key: &KeyEvent| match key.code {
+            KeyCode::Enter => Some('\n'),
+            KeyCode::Tab => Some('\t'),
+            _ => character(key),
+        };
+        return match keys.get(1).map(argument) {
+            None => Parsed::Pending,
+            Some(Some(argument)) => {
+                Parsed::Complete(Action::WithChar(command, argument, count), register)
+            }
+            Some(None) => Parsed::Invalid,
+        };
+    }
+
+    if current_mode.is_visual() {
+        return match (found, text_object(keys)) {
+            (_, Lookup::Found((movement, direction), _)) => {
+                Parsed::Complete(Action::Move(movement, direction, count), register)
+            }
+            (Lookup::Incomplete, _) | (_, Lookup::Incomplete) => Parsed::Pending,
+            _ => Parsed::Complete(Action::Other(*key, count), register),
+        };
+    }
+
+    if let Lookup::Found((operator, movement, direction), _) = lookup(SHORTCUTS, keys) {
+        return Parsed::Complete(
+            Action::Operate(operator, movement, direction, count),
+            register,
+        );
+    }
+
+    let operator = lookup(OPERATORS, keys);
+    let (operator, length) = match (operator, found) {
+        (Lookup::Found(operator, length), _) => (operator, length),
+        (Lookup::Incomplete, _) | (_, Lookup::Incomplete) => return Parsed::Pending,
+        _ => return Parsed::Complete(Action::Other(*key, count), register),
+    };
+
+    let (motion_count, rest) = parse_count(&keys[length..]);
+    let count = count.times(motion_count);
+    if rest.is_empty() {
+        return Parsed::OperatorPending;
+    }
+
+    // A doubled operator works on lines, so does repeating the last key of gu, gU and g~
+    if rest.starts_with(&keys[..length]) || (length > 1 && rest.first() == keys.get(length - 1)) {
+        return Parsed::Complete(
+            Action::Operate(operator, MovementType::CurrentLine, Direction::Down, count),
+            register,
+        );
+    }
+
+    let found = match motion(rest) {
+        Lookup::NotFound => text_object(rest),
+        found => found,
+    };
+    match found {
+        Lookup::Found((movement, direction), _) => Parsed::Complete(
+            Action::Operate(operator, movement, direction, count),
+            register,
+        ),
+        Lookup::Incomplete => Parsed::OperatorPending,
+        Lookup::NotFound => Parsed::Invalid,
+    }
+}
+
+// Splits a leading count off the keys, a count can't start with 0
+fn parse_count(keys: &[KeyEvent]) -> (Count, &[KeyEvent]) {
+    let mut count = Count::Default;
+
+    for (index, key) in keys.iter().enumerate() {
+        let digit = match character(key).and_then(|c| c.to_digit(10)) {
+            Some(0) if count == Count::Default => None,
+            digit => digit,
+        };
+
+        match digit {
+            Some(digit) => {
+                count = Count::Number(
+                    count
+                        .or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit as usize),
+                );
+            }
+            None => return (count, &keys[index..]),
+        }
+    }
+
+    (count, &[])
+}
+
+fn character(key: &KeyEvent) -> Option<char> {
+    match (key.code, key.modifiers) {
+        (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => Some(c),
+        _ => None,
+    }
+}
+
+// Looks up the motion the keys start with, f, F, t, T, ' and ` take the character after them
+fn motion(keys: &[KeyEvent]) -> Lookup<(MovementType, Direction)> {
+    if let Some(mark @ ('\'' | '`')) = keys.first().and_then(character) {
+        return match keys.get(1).map(character) {
+            None => Lookup::Incomplete,
+            Some(Some(name)) => Lookup::Found(
+                (
+                    MovementType::Mark {
+                        name,
+                        linewise: mark == '\'',
+                    },
+                    Direction::Forward,
+                ),
+                2,
+            ),
+            Some(None) => Lookup::NotFound,
+        };
+    }
+
+    let (till, direction) = match keys.first().and_then(character) {
+        Some('f') => (false, Direction::Forward),
+        Some('F') => (false, Direction::Backward),
+        Some('t') => (true, Direction::Forward),
+        Some('T') => (true, Direction::Backward),
+        _ => return lookup(MOTIONS, keys),
+    };
+
+    match keys.get(1).map(character) {
+        None => Lookup::Incomplete,
+        Some(Some(target)) => Lookup::Found(
+            (
+                MovementType::Find {
+                    target,
+                    till,
+                    repeat: false,
+                },
+                direction,
+            ),
+            2,
+        ),
+        Some(None) => Lookup::NotFound,
+    }
+}
+
+// Looks up the text object the keys start with, like iw or a"
+fn text_object(keys: &[KeyEvent]) -> Lookup<(MovementType, Direction)> {
+    let inner = match keys.first().and_then(character) {
+        Some('i') => true,
+        Some('a') => false,
+        _ => return Lookup::NotFound,
+    };
+
+    match lookup(TEXT_OBJECTS, &keys[1..]) {
+        Lookup::Found(object, length) => Lookup::Found(
+            (
+                MovementType::TextObject { object, inner },
+                Direction::Forward,
+            ),
+            length + 1,
+        ),
+        Lookup::Incomplete => Lookup::Incomplete,
+        Lookup::NotFound => Lookup::NotFound,
+    }
+}
+
+// Keys as they are shown while a command is pending, like 2"ad
+pub fn display(keys: &[KeyEvent]) -> String {
+    keys.iter()
+        .map(|key| match (key.code, key.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::CONTROL) => format!("^{}", c.to_ascii_uppercase()),
+            (KeyCode::Char(c), _) => c.to_string(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+// Keys of a macro as the text of a register, so it can be edited
+pub fn to_text(keys: &[KeyEvent]) -> String {
+    keys.iter()
+        .filter_map(|key| match (key.code, key.modifiers) {
+            (KeyCode::Char(c), KeyModifiers::CONTROL) if c.is_ascii_alphabetic() => {
+                char::from_u32(c.to_ascii_lowercase() as u32 & 0x1f)
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => Some(c),
+            (code, _) => SPECIAL_KEYS
+                .iter()
+                .find(|(special, _)| *special == code)
+                .map(|(_, c)| *c),
+        })
+        .collect()
+}
+
+pub fn from_text(text: &str) -> Vec<KeyEvent> {
+    text.chars()
+        .map(|c| {
+            if let Some((code, _)) = SPECIAL_KEYS.iter().find(|(_, special)| *special == c) {
+                return KeyEvent::new(*code, KeyModifiers::NONE);
+            }
+
+            match c as u32 {
+                1..=26 => KeyEvent::new(
+                    KeyCode::Char(char::from(b'a' + c as u8 - 1)),
+                    KeyModifiers::CONTROL,
+                ),
+                _ if c.is_uppercase() => KeyEvent::new(KeyCode::Char(c), KeyModifiers::SHIFT),
+                _ => KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+            }
+        })
+        .collect()
+}
+
+fn lookup<T: Copy>(table: &[(&str, T)], keys: &[KeyEvent]) -> Lookup<T> {
+    let typed: String = keys.iter().map_while(character).collect();
+    let complete = typed.chars().count() == keys.len();
+    let mut result = Lookup::NotFound;
+
+    for (pattern, value) in table {
+        if typed.starts_with(pattern) {
+            return Lookup::Found(*value, pattern.chars().count());
+        }
+        if complete && pattern.starts_with(&typed) {
+            result = Lookup::Incomplete;
+        }
+    }
+
+    result
 }
@@ -0,0 +1,470 @@
+use std::cmp;
+
+// Smallest size a window can be shrunk to, a row of text and the status line
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SplitDirection {
+    // Windows stacked on top of each other
+    Horizontal,
+    // Windows side by side, separated by a vertical bar
+    Vertical,
+}
+
+impl SplitDirection {
+    fn separator(&self) -> usize {
+        match self {
+            SplitDirection::Horizontal => 0,
+            SplitDirection::Vertical => 1,
+        }
+    }
+
+    fn min_size(&self) -> usize {
+        match self {
+            SplitDirection::Horizontal => MIN_HEIGHT,
+            SplitDirection::Vertical => MIN_WIDTH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+// Tree of windows, every split keeps the size of its children along its direction
+pub enum Layout {
+    Window(usize),
+    Split {
+        direction: SplitDirection,
+        children: Vec<(Layout, usize)>,
+    },
+}
+
+impl Layout {
+    pub fn new(window: usize) -> Self {
+        Layout::Window(window)
+    }
+
+    pub fn contains(&self, window: usize) -> bool {
+        match self {
+            Layout::Window(id) => *id == window,
+            Layout::Split { children, .. } => {
+                children.iter().any(|(child, _)| child.contains(window))
+            }
+        }
+    }
+
+    // Window ids from top left to bottom right
+    pub fn windows(&self) -> Vec<usize> {
+        match self {
+            Layout::Window(id) => vec![*id],
+            Layout::Split { children, .. } => children
+                .iter()
+                .flat_map(|(child, _)| child.windows())
+                .collect(),
+        }
+    }
+
+    // Position of every window, and of the vertical separators between them
+    pub fn rects(&self, area: Rect) -> (Vec<(usize, Rect)>, Vec<Rect>) {
+        let mut windows = Vec::new();
+        let mut separators = Vec::new();
+        self.compute(area, &mut windows, &mut separators);
+        (windows, separators)
+    }
+
+    pub fn rect(&self, area: Rect, window: usize) -> Option<Rect> {
+        self.rects(area)
+            .0
+            .into_iter()
+            .find(|(id, _)| *id == window)
+            .map(|(_, rect)| rect)
+    }
+
+    fn compute(&self, area: Rect, windows: &mut Vec<(usize, Rect)>, separators: &mut Vec<Rect>) {
+        match self {
+            Layout::Window(id) => windows.push((*id, area)),
+            Layout::Split {
+                direction,
+                children,
+            } => {
+                let separator = direction.separator();
+                let total = match direction {
+                    SplitDirection::Horizontal => area.height,
+                    SplitDirection::Vertical => area.width,
+                };
+                let available = total.saturating_sub(separator * (children.len() - 1));
+                let sizes: Vec<usize> = children.iter().map(|(_, size)| *size).collect();
+                let mut start = 0;
+
+                for (index, ((child, _), size)) in children
+                    .iter()
+                    .zip(distribute(&sizes, available))
+                    .enumerate()
+                {
+                    let rect = match direction {
+                        SplitDirection::Horizontal => {
+                            Rect::new(area.x, area.y + start, area.width, size)
+                        }
+                        SplitDirection::Vertical => {
+                            Rect::new(area.x + start, area.y, size, area.height)
+                        }
+                    };
+                    child.compute(rect, windows, separators);
+                    start += size;
+
+                    if separator > 0 && index + 1 < children.len() {
+                        separators.push(Rect::new(area.x + start, area.y, separator, area.height));
+                        start += separator;
+                    }
+                }
+            }
+        }
+    }
+
+    // Splits the target window in two, the new window goes above or to the left of it.
+    // `size` is the current size of the target along the split direction.
+    pub fn split(
+        &mut self,
+        target: usize,
+        window: usize,
+        direction: SplitDirection,
+        size: usize,
+    ) -> bool {
+        let separator = direction.separator();
+        if size < direction.min_size() * 2 + separator {
+            return false;
+        }
+
+        let new_size = size.saturating_sub(separator) / 2;
+        let old_size = size.saturating_sub(separator) - new_size;
+
+        match self {
+            Layout::Window(id) if *id == target => {
+                *self = Layout::Split {
+                    direction,
+                    children: vec![
+                        (Layout::Window(window), new_size),
+                        (Layout::Window(target), old_size),
+                    ],
+                };
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split {
+                direction: split_direction,
+                children,
+            } => {
+                let index = match children
+                    .iter()
+                    .position(|(child, _)| child.contains(target))
+                {
+                    Some(index) => index,
+                    None => return false,
+                };
+
+                // Add a sibling when the split already goes in the right direction
+                if *split_direction == direction
+                    && matches!(children[index].0, Layout::Window(id) if id == target)
+                {
+                    children[index].1 = old_size;
+                    children.insert(index, (Layout::Window(window), new_size));
+                    return true;
+                }
+
+                children[index].0.split(target, window, direction, size)
+            }
+        }
+    }
+
+    // Removes a window and gives its space to a neighbour, the last window can't be removed
+    pub fn remove(&mut self, target: usize) -> bool {
+        let (direction, children) = match self {
+            Layout::Window(_) => return false,
+            Layout::Split {
+                direction,
+                children,
+            } => (direction, children),
+        };
+
+        let index = match children
+            .iter()
+            .position(|(child, _)| child.contains(target))
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        if !matches!(children[index].0, Layout::Window(id) if id == target) {
+            return children[index].0.remove(target);
+        }
+
+        let (_, size) = children.remove(index);
+        let neighbour = index.saturating_sub(1).min(children.len() - 1);
+        children[neighbour].1 += size + direction.separator();
+
+        // A split with a single child is just that child
+        if children.len() == 1 {
+            let (child, _) = children.remove(0);
+            *self = child;
+        }
+
+        true
+    }
+
+    // Grows (or shrinks, with a negative delta) the target window along the given direction
+    pub fn resize(&mut self, target: usize, direction: SplitDirection, delta: isize) -> bool {
+        let (split_direction, children) = match self {
+            Layout::Window(_) => return false,
+            Layout::Split {
+                direction,
+                children,
+            } => (direction, children),
+        };
+
+        let index = match children
+            .iter()
+            .position(|(child, _)| child.contains(target))
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        // The closest split in the right direction is the one that gets resized
+        if children[index].0.resize(target, direction, delta) {
+            return true;
+        }
+        if *split_direction != direction || children.len() < 2 {
+            return false;
+        }
+
+        let neighbour = if index + 1 < children.len() {
+            index + 1
+        } else {
+            index - 1
+        };
+        let min = direction.min_size();
+        let combined = children[index].1 + children[neighbour].1;
+        let size = (children[index].1 as isize + delta)
+            .clamp(min as isize, combined.saturating_sub(min) as isize) as usize;
+
+        children[index].1 = cmp::max(size, min);
+        children[neighbour].1 = combined.saturating_sub(children[index].1);
+        true
+    }
+
+    // Gives every window of each split the same size
+    pub fn equalize(&mut self) {
+        if let Layout::Split { children, .. } = self {
+            let total: usize = children.iter().map(|(_, size)| *size).sum();
+            let count = children.len();
+
+            for (index, (child, size)) in children.iter_mut().enumerate() {
+                *size = total / count + usize::from(index < total % count);
+                child.equalize();
+            }
+        }
+    }
+
+    // Window next to the target one on the given side, closest to the cursor
+    pub fn neighbour(
+        &self,
+        area: Rect,
+        target: usize,
+        side: Side,
+        cursor: (usize, usize),
+    ) -> Option<usize> {
+        let (windows, _) = self.rects(area);
+        let current = windows.iter().find(|(id, _)| *id == target)?.1;
+        let (cursor_x, cursor_y) = cursor;
+
+        windows
+            .iter()
+            .filter(|(id, rect)| {
+                *id != target
+                    && match side {
+                        Side::Left => {
+                            rect.x + rect.width <= current.x
+                                && overlaps(rect.y, rect.height, &current, false)
+                        }
+                        Side::Right => {
+                            rect.x >= current.x + current.width
+                                && overlaps(rect.y, rect.height, &current, false)
+                        }
+                        Side::Up => {
+                            rect.y + rect.height <= current.y
+                                && overlaps(rect.x, rect.width, &current, true)
+                        }
+                        Side::Down => {
+                            rect.y >= current.y + current.height
+                                && overlaps(rect.x, rect.width, &current, true)
+                        }
+                    }
+            })
+            .min_by_key(|(_, rect)| {
+                // Closest window first, then the one lined up with the cursor
+                let distance = match side {
+                    Side::Left => current.x - (rect.x + rect.width),
+                    Side::Right => rect.x - (current.x + current.width),
+                    Side::Up => current.y - (rect.y + rect.height),
+                    Side::Down => rect.y - (current.y + current.height),
+                };
+                let alignment = match side {
+                    Side::Left | Side::Right => gap(rect.y, rect.height, cursor_y),
+                    Side::Up | Side::Down => gap(rect.x, rect.width, cursor_x),
+                };
+                (distance, alignment)
+            })
+            .map(|(id, _)| *id)
+    }
+}
+
+// Whether the span overlaps the rect horizontally (or vertically)
+fn overlaps(start: usize, length: usize, rect: &Rect, horizontal: bool) -> bool {
+    let (rect_start, rect_length) = if horizontal {
+        (rect.x, rect.width)
+    } else {
+        (rect.y, rect.height)
+    };
+
+    start < rect_start + rect_length && rect_start < start + length
+}
+
+// Distance between a point and a span, zero when the point is inside
+fn gap(start: usize, length: usize, point: usize) -> usize {
+    if point < start {
+        start - point
+    } else if point >= start + length {
+        point + 1 - (start + length)
+    } else {
+        0
+    }
+}
+
+// Scales the sizes so they add up to the available space
+fn distribute(sizes: &[usize], available: usize) -> Vec<usize> {
+    let total: usize = sizes.iter().sum();
+    if total == available || total == 0 {
+        return sizes.to_vec();
+    }
+
+    let mut result: Vec<usize> = sizes.iter().map(|size| size * available / total).collect();
+    let used: usize = result.iter().sum();
+    if let Some(last) = result.last_mut() {
+        *last += available.saturating_sub(used);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 80,
+        height: 24,
+    };
+
+    // Window 1 on the left, window 2 above window 0 on the right
+    fn nested() -> Layout {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDirection::Vertical, 80));
+        assert!(layout.split(0, 2, SplitDirection::Horizontal, 24));
+        layout
+    }
+
+    #[test]
+    fn split_puts_the_new_window_above() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDirection::Horizontal, 24));
+
+        let (windows, separators) = layout.rects(AREA);
+        assert_eq!(
+            windows,
+            [(1, Rect::new(0, 0, 80, 12)), (0, Rect::new(0, 12, 80, 12))]
+        );
+        assert!(separators.is_empty());
+    }
+
+    #[test]
+    fn vertical_split_leaves_room_for_a_separator() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDirection::Vertical, 80));
+
+        let (windows, separators) = layout.rects(AREA);
+        assert_eq!(
+            windows,
+            [(1, Rect::new(0, 0, 39, 24)), (0, Rect::new(40, 0, 40, 24))]
+        );
+        assert_eq!(separators, [Rect::new(39, 0, 1, 24)]);
+    }
+
+    #[test]
+    fn split_fails_when_the_window_is_too_small() {
+        let mut layout = Layout::new(0);
+        assert!(!layout.split(0, 1, SplitDirection::Horizontal, 3));
+        assert_eq!(layout.windows(), [0]);
+    }
+
+    #[test]
+    fn removing_the_other_window_of_a_split_leaves_a_single_window() {
+        let mut layout = Layout::new(0);
+        layout.split(0, 1, SplitDirection::Horizontal, 24);
+
+        assert!(layout.remove(1));
+        assert!(matches!(layout, Layout::Window(0)));
+        assert_eq!(layout.rects(AREA).0, [(0, AREA)]);
+        assert!(!layout.remove(0));
+    }
+
+    #[test]
+    fn removing_a_nested_window_gives_its_space_to_its_sibling() {
+        let mut layout = nested();
+
+        assert!(layout.remove(2));
+        assert_eq!(
+            layout.rects(AREA).0,
+            [(1, Rect::new(0, 0, 39, 24)), (0, Rect::new(40, 0, 40, 24))]
+        );
+    }
+
+    #[test]
+    fn neighbour_goes_across_nested_splits() {
+        let layout = nested();
+
+        assert_eq!(layout.neighbour(AREA, 1, Side::Right, (5, 20)), Some(0));
+        assert_eq!(layout.neighbour(AREA, 1, Side::Right, (5, 3)), Some(2));
+        assert_eq!(layout.neighbour(AREA, 0, Side::Left, (50, 20)), Some(1));
+        assert_eq!(layout.neighbour(AREA, 2, Side::Left, (50, 3)), Some(1));
+        assert_eq!(layout.neighbour(AREA, 0, Side::Up, (50, 20)), Some(2));
+        assert_eq!(layout.neighbour(AREA, 2, Side::Down, (50, 3)), Some(0));
+        assert_eq!(layout.neighbour(AREA, 0, Side::Down, (50, 20)), None);
+        assert_eq!(layout.neighbour(AREA, 1, Side::Left, (5, 3)), None);
+    }
+}
@@ -1,16 +1,31 @@
+mod buffer;
+mod clipboard;
 mod command;
 mod document;
 mod editor;
+mod fileformat;
 mod filetype;
 mod highlighting;
+mod history;
 mod keymaps;
+mod layout;
+mod marks;
 mod mode;
+mod motion;
+mod operator;
+mod pattern;
 mod position;
+mod registers;
 mod row;
+mod selection;
 mod settings;
 mod statusbar;
+mod substitute;
+mod swap;
 mod terminal;
+mod textobject;
 mod utils;
+mod window;
 
 pub use document::Document;
 pub use editor::Editor;
@@ -26,12 +41,18 @@ pub use terminal::Terminal;
 use log::info;
 use simplelog::{Config, LevelFilter, WriteLogger};
 use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
 
 fn main() {
     init_logging();
     info!("Starting Miv");
 
-    Editor::new(Settings::new()).run();
+    let mut editor = Editor::new(Settings::new());
+
+    if let Err(error) = panic::catch_unwind(AssertUnwindSafe(|| editor.run())) {
+        editor.write_swap_file();
+        panic::resume_unwind(error);
+    }
 }
 
 fn init_logging() {
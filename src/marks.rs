@@ -0,0 +1,160 @@
+use crate::Position;
+use std::collections::{HashMap, VecDeque};
+
+// Marks of a document, kept on the same text as it is edited.
+// a to z are local to the document, A to Z are global and live in the document they were set in,
+// ' is where the cursor was before the last jump.
+#[derive(Default)]
+pub struct Marks {
+    positions: HashMap<char, Position>,
+    // Rows :g still has to visit, in order
+    lines: VecDeque<Position>,
+}
+
+impl Marks {
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphabetic() || matches!(name, '\'' | '`')
+    }
+
+    pub fn get(&self, name: char) -> Option<&Position> {
+        self.positions.get(&normalize(name))
+    }
+
+    pub fn set(&mut self, name: char, position: Position) {
+        self.positions.insert(normalize(name), position);
+    }
+
+    pub fn remove(&mut self, name: char) -> Option<Position> {
+        self.positions.remove(&normalize(name))
+    }
+
+    // Global marks, to remember them when the document is closed
+    pub fn global(&self) -> impl Iterator<Item = (char, &Position)> {
+        self.positions
+            .iter()
+            .filter(|(name, _)| name.is_ascii_uppercase())
+            .map(|(name, position)| (*name, position))
+    }
+
+    pub fn set_lines(&mut self, rows: &[usize]) {
+        self.lines = rows.iter().map(|&y| Position::new(0, y)).collect();
+    }
+
+    // The next row to visit, where it is now
+    pub fn next_line(&mut self) -> Option<usize> {
+        self.lines.pop_front().map(|position| position.y)
+    }
+
+    pub fn clear_lines(&mut self) {
+        self.lines.clear();
+    }
+
+    // Text was inserted from start up to end, marks after it move along
+    pub fn inserted(&mut self, start: &Position, end: &Position) {
+        // Line marks are in order, the ones before the text stay where they are
+        let first = self.lines.partition_point(|position| position < start);
+        let lines = self.lines.range_mut(first..);
+
+        for position in self.positions.values_mut().chain(lines) {
+            shift_inserted(position, start, end);
+        }
+    }
+
+    // Text was deleted from start up to end (exclusive), on a row of end_len graphemes.
+    // The marks of rows deleted entirely go away, the ones in deleted text move to where it was.
+    pub fn deleted(&mut self, start: &Position, end: &Position, end_len: usize) {
+        self.positions
+            .retain(|_, position| !row_deleted(position, start, end, end_len));
+
+        // Only the line marks from the start of the text up to its last row can go away,
+        // a row joined to the one above is gone too
+        let first = self.lines.partition_point(|position| position < start);
+        let mut index = first;
+        while index < self.lines.len() && self.lines[index].y <= end.y {
+            let position = &self.lines[index];
+            let joined = start < position && position < end;
+            if row_deleted(position, start, end, end_len) || joined {
+                self.lines.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+        let lines = self.lines.range_mut(first..);
+
+        for position in self.positions.values_mut().chain(lines) {
+            shift_deleted(position, start, end);
+        }
+    }
+}
+
+fn row_deleted(position: &Position, start: &Position, end: &Position, end_len: usize) -> bool {
+    // Deleting from the start of a row to the start of an empty one keeps the empty one
+    let to_row_end = end.x >= end_len && (start.x > 0 || end.x > 0);
+
+    (start.x == 0 && start.y == position.y && end.y > position.y)
+        || (start.y < position.y && end.y == position.y && to_row_end)
+        || (start.y < position.y && end.y > position.y)
+}
+
+fn shift_inserted(position: &mut Position, start: &Position, end: &Position) {
+    if *position < *start {
+        return;
+    }
+
+    if position.y == start.y {
+        position.x = end.x + position.x - start.x;
+    }
+    position.y += end.y - start.y;
+}
+
+fn shift_deleted(position: &mut Position, start: &Position, end: &Position) {
+    if *position < *start {
+        return;
+    }
+
+    if *position < *end {
+        *position = start.clone();
+    } else if position.y == end.y {
+        position.x = start.x + position.x - end.x;
+        position.y = start.y;
+    } else {
+        position.y -= end.y - start.y;
+    }
+}
+
+// ` and ' are the same mark
+fn normalize(name: char) -> char {
+    match name {
+        '`' => '\'',
+        name => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(marks: &mut Marks) -> Vec<usize> {
+        std::iter::from_fn(|| marks.next_line()).collect()
+    }
+
+    #[test]
+    fn lines_move_with_inserted_rows() {
+        let mut marks = Marks::default();
+        marks.set_lines(&[1, 3, 5]);
+
+        // A line break in the middle of row 2
+        marks.inserted(&Position::new(2, 2), &Position::new(0, 3));
+        assert_eq!(lines(&mut marks), [1, 4, 6]);
+    }
+
+    #[test]
+    fn lines_of_deleted_rows_go_away() {
+        let mut marks = Marks::default();
+        marks.set_lines(&[1, 3, 4, 6]);
+
+        // Rows 3 and 4, up to the start of row 5
+        marks.deleted(&Position::new(0, 3), &Position::new(0, 5), 5);
+        assert_eq!(lines(&mut marks), [1, 4]);
+    }
+}
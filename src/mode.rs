@@ -2,12 +2,16 @@ use crate::Terminal;
 use crossterm::{cursor::SetCursorStyle, style::Color};
 use std::fmt::Display;
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy, PartialEq)]
 pub enum PossibleModes {
     #[default]
     Normal,
     Insert,
+    // Typed text overwrites the text under the cursor
+    Replace,
     Visual,
+    VisualLine,
+    VisualBlock,
     Command,
     Search,
     OperatorPending,
@@ -23,7 +27,10 @@ impl Display for PossibleModes {
         let mode = match self {
             PossibleModes::Normal => "Normal",
             PossibleModes::Insert => "Insert",
+            PossibleModes::Replace => "Replace",
             PossibleModes::Visual => "Visual",
+            PossibleModes::VisualLine => "Visual Line",
+            PossibleModes::VisualBlock => "Visual Block",
             PossibleModes::Command => "Command",
             PossibleModes::Search => "Search",
             // TODO: Implement operator and motion pending modes
@@ -34,11 +41,26 @@ impl Display for PossibleModes {
 }
 
 impl PossibleModes {
+    pub fn is_visual(&self) -> bool {
+        matches!(
+            self,
+            PossibleModes::Visual | PossibleModes::VisualLine | PossibleModes::VisualBlock
+        )
+    }
+
+    // Modes where typed text goes into the document
+    pub fn is_insert(&self) -> bool {
+        matches!(self, PossibleModes::Insert | PossibleModes::Replace)
+    }
+
     pub fn to_color(&self) -> Color {
         match self {
             PossibleModes::Normal => Color::Blue,
             PossibleModes::Insert => Color::Red,
-            PossibleModes::Visual => Color::Green,
+            PossibleModes::Replace => Color::DarkRed,
+            PossibleModes::Visual | PossibleModes::VisualLine | PossibleModes::VisualBlock => {
+                Color::Green
+            }
             PossibleModes::Command => Color::Magenta,
             PossibleModes::Search => Color::Yellow,
             PossibleModes::OperatorPending => Color::Cyan,
@@ -57,9 +79,13 @@ impl Mode {
                 Terminal::set_cursor(SetCursorStyle::BlinkingBar);
                 PossibleModes::Insert
             }
-            PossibleModes::Visual => {
+            PossibleModes::Replace => {
+                Terminal::set_cursor(SetCursorStyle::BlinkingUnderScore);
+                PossibleModes::Replace
+            }
+            PossibleModes::Visual | PossibleModes::VisualLine | PossibleModes::VisualBlock => {
                 Terminal::set_cursor(SetCursorStyle::SteadyBlock);
-                PossibleModes::Visual
+                new_mode
             }
             PossibleModes::OperatorPending => {
                 Terminal::set_cursor(SetCursorStyle::SteadyUnderScore);
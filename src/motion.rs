@@ -0,0 +1,493 @@
+use crate::keymaps::{Direction, MovementType};
+use crate::operator::Operator;
+use crate::selection::{Selection, SelectionKind};
+use crate::textobject;
+use crate::{Document, Position, Row};
+use std::cmp;
+use std::rc::Rc;
+
+const BRACKETS: &[(&str, &str)] = &[("(", ")"), ("[", "]"), ("{", "}")];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Space,
+    Word,
+    Punctuation,
+}
+
+// Where the motion takes the cursor, None when it can't move
+pub fn target(
+    document: &Document,
+    from: &Position,
+    movement: MovementType,
+    direction: Direction,
+    count: usize,
+) -> Option<Position> {
+    let last_row = document.len().saturating_sub(1);
+    let y = from.y;
+    let x = cmp::min(from.x, document.row_len(y));
+
+    let target = match (movement, direction) {
+        (MovementType::Character, Direction::Backward) => Position::new(x.saturating_sub(count), y),
+        (MovementType::Character, Direction::Forward) => {
+            Position::new(cmp::min(x + count, document.row_len(y)), y)
+        }
+        (MovementType::Line, Direction::Up) => {
+            let y = y.saturating_sub(count);
+            Position::new(cmp::min(x, document.row_len(y)), y)
+        }
+        (MovementType::Line, Direction::Down) => {
+            let y = cmp::min(y.saturating_add(count), last_row);
+            Position::new(cmp::min(x, document.row_len(y)), y)
+        }
+        (MovementType::LineBoundary, Direction::Backward) => Position::new(0, y),
+        (MovementType::LineBoundary, Direction::Forward) => {
+            let y = cmp::min(y + count - 1, last_row);
+            Position::new(document.row_len(y), y)
+        }
+        (MovementType::CurrentLine, _) => {
+            let y = cmp::min(y + count - 1, last_row);
+            Position::new(x, y)
+        }
+        (MovementType::Word | MovementType::BigWord, _)
+        | (MovementType::WordEnd | MovementType::BigWordEnd, _) => {
+            let big = matches!(movement, MovementType::BigWord | MovementType::BigWordEnd);
+            let mut walker = Walker::new(document, Position::new(x, y), big);
+            for _ in 0..count {
+                match (movement, direction) {
+                    (MovementType::Word | MovementType::BigWord, Direction::Forward) => {
+                        walker.word_start()
+                    }
+                    (MovementType::Word | MovementType::BigWord, _) => walker.previous_word_start(),
+                    (_, Direction::Forward) => walker.word_end(),
+                    _ => walker.previous_word_end(),
+                }
+            }
+            walker.position()
+        }
+        (
+            MovementType::Find {
+                target,
+                till,
+                repeat,
+            },
+            _,
+        ) => {
+            let row = row(document, y);
+            let x = find(
+                &row,
+                x,
+                &target.to_string(),
+                direction,
+                count,
+                till && repeat,
+            )?;
+
+            // t and T stop next to the character
+            match (till, direction) {
+                (true, Direction::Forward) => Position::new(x - 1, y),
+                (true, _) => Position::new(x + 1, y),
+                _ => Position::new(x, y),
+            }
+        }
+        (MovementType::LineNumber, _) => {
+            let y = cmp::min(count.saturating_sub(1), last_row);
+            Position::new(first_non_blank(document, y), y)
+        }
+        (MovementType::MatchingBracket, _) => matching_bracket(document, x, y)?,
+        (MovementType::Paragraph, Direction::Forward) => {
+            let mut y = y;
+            for _ in 0..count {
+                while y < last_row && document.row_len(y) == 0 {
+                    y += 1;
+                }
+                while y < last_row && document.row_len(y) != 0 {
+                    y += 1;
+                }
+            }
+            // Past the last paragraph, the end of the file
+            Position::new(document.row_len(y), y)
+        }
+        (MovementType::Paragraph, _) => {
+            let mut y = y;
+            for _ in 0..count {
+                while y > 0 && document.row_len(y) == 0 {
+                    y -= 1;
+                }
+                while y > 0 && document.row_len(y) != 0 {
+                    y -= 1;
+                }
+            }
+            Position::new(0, y)
+        }
+        (MovementType::Mark { name, linewise }, _) => {
+            let mark = document.mark(name)?;
+            let y = cmp::min(mark.y, last_row);
+            if linewise {
+                Position::new(first_non_blank(document, y), y)
+            } else {
+                Position::new(cmp::min(mark.x, document.row_len(y)), y)
+            }
+        }
+        _ => return None,
+    };
+
+    // Lines and marks are still a selection when the cursor is on them
+    let stays = matches!(
+        movement,
+        MovementType::CurrentLine | MovementType::LineNumber | MovementType::Mark { .. }
+    );
+    if !stays && target == Position::new(x, y) {
+        return None;
+    }
+    Some(target)
+}
+
+// Text covered by a motion, for the given operator
+pub fn selection(
+    document: &Document,
+    from: &Position,
+    operator: Operator,
+    (movement, direction): (MovementType, Direction),
+    count: usize,
+) -> Option<Selection> {
+    let from = Position::new(
+        cmp::min(from.x, document.row_len(from.y)),
+        cmp::min(from.y, document.len().saturating_sub(1)),
+    );
+
+    if let MovementType::TextObject { object, inner } = movement {
+        return textobject::selection(document, &from, object, inner, count);
+    }
+
+    // cw and cW on a word stop at its end, like ce, instead of taking the blank after it
+    let big = movement == MovementType::BigWord;
+    let mut walker = Walker::new(document, from.clone(), big);
+    if operator == Operator::Change
+        && matches!(movement, MovementType::Word | MovementType::BigWord)
+        && direction == Direction::Forward
+        && walker.class() != CharClass::Space
+    {
+        let class = walker.class();
+        while walker.next_class() == class {
+            walker.forward();
+        }
+        for _ in 1..count {
+            walker.word_end();
+        }
+
+        let end = walker.position();
+        return Some(Selection {
+            start: from,
+            end: Position::new(cmp::min(end.x + 1, document.row_len(end.y)), end.y),
+            kind: SelectionKind::Charwise,
+        });
+    }
+
+    let to = target(document, &from, movement, direction, count)?;
+    let (start, mut end) = if from <= to { (from, to) } else { (to, from) };
+
+    if matches!(
+        movement,
+        MovementType::Line
+            | MovementType::CurrentLine
+            | MovementType::LineNumber
+            | MovementType::Mark { linewise: true, .. }
+    ) {
+        return Some(Selection {
+            start: Position::new(0, start.y),
+            end: Position::new(0, end.y),
+            kind: SelectionKind::Linewise,
+        });
+    }
+
+    if is_inclusive(movement, direction) {
+        end.x = cmp::min(end.x + 1, document.row_len(end.y));
+    } else if end.x == 0 && end.y > start.y {
+        // A motion ending at the start of a later row stops at the end of the row before it,
+        // and covers whole rows when it starts before the text of its row (except for words)
+        if start.x <= first_non_blank(document, start.y)
+            && !matches!(movement, MovementType::Word | MovementType::BigWord)
+        {
+            return Some(Selection {
+                start: Position::new(0, start.y),
+                end: Position::new(0, end.y - 1),
+                kind: SelectionKind::Linewise,
+            });
+        }
+        end = Position::new(document.row_len(end.y - 1), end.y - 1);
+    }
+
+    Some(Selection {
+        start,
+        end,
+        kind: SelectionKind::Charwise,
+    })
+}
+
+// Inclusive motions cover the character they end on
+fn is_inclusive(movement: MovementType, direction: Direction) -> bool {
+    match movement {
+        MovementType::WordEnd | MovementType::BigWordEnd => true,
+        MovementType::Find { .. } => direction == Direction::Forward,
+        MovementType::MatchingBracket => true,
+        _ => false,
+    }
+}
+
+// Column of the count-th grapheme matching the target on either side of x
+fn find(
+    row: &Row,
+    x: usize,
+    target: &str,
+    direction: Direction,
+    count: usize,
+    skip_next: bool,
+) -> Option<usize> {
+    let mut at = x;
+    if skip_next {
+        at = match direction {
+            Direction::Forward => at + 1,
+            _ => at.checked_sub(1)?,
+        };
+    }
+
+    for _ in 0..count {
+        at = match direction {
+            Direction::Forward => {
+                (at + 1..row.len()).find(|&index| row.grapheme(index) == Some(target))?
+            }
+            _ => (0..at)
+                .rev()
+                .find(|&index| row.grapheme(index) == Some(target))?,
+        };
+    }
+
+    Some(at)
+}
+
+// Vim's word classes, for WORDs anything that isn't blank is a word character
+pub fn class(grapheme: &str, big: bool) -> CharClass {
+    match grapheme.chars().next() {
+        Some(c) if c.is_whitespace() => CharClass::Space,
+        _ if big => CharClass::Word,
+        Some(c) if c.is_alphanumeric() || c == '_' => CharClass::Word,
+        _ => CharClass::Punctuation,
+    }
+}
+
+// Steps through the graphemes of a document, the end of every row counts as a blank
+struct Walker<'a> {
+    document: &'a Document,
+    x: usize,
+    y: usize,
+    row: Rc<Row>,
+    big: bool,
+}
+
+impl<'a> Walker<'a> {
+    fn new(document: &'a Document, position: Position, big: bool) -> Self {
+        let row = row(document, position.y);
+        Self {
+            document,
+            x: position.x,
+            y: position.y,
+            row,
+            big,
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position::new(self.x, self.y)
+    }
+
+    fn class(&self) -> CharClass {
+        self.row
+            .grapheme(self.x)
+            .map_or(CharClass::Space, |grapheme| class(grapheme, self.big))
+    }
+
+    fn next_class(&self) -> CharClass {
+        self.row
+            .grapheme(self.x + 1)
+            .map_or(CharClass::Space, |grapheme| class(grapheme, self.big))
+    }
+
+    fn previous_class(&self) -> Option<CharClass> {
+        let x = self.x.checked_sub(1)?;
+        self.row
+            .grapheme(x)
+            .map(|grapheme| class(grapheme, self.big))
+    }
+
+    // An empty row counts as a word
+    fn on_empty_row(&self) -> bool {
+        self.row.is_empty()
+    }
+
+    fn forward(&mut self) -> bool {
+        if self.x < self.row.len() {
+            self.x += 1;
+            return true;
+        }
+        if self.y + 1 >= self.document.len() {
+            return false;
+        }
+
+        self.y += 1;
+        self.x = 0;
+        self.row = row(self.document, self.y);
+        true
+    }
+
+    fn backward(&mut self) -> bool {
+        if self.x > 0 {
+            self.x -= 1;
+            return true;
+        }
+        if self.y == 0 {
+            return false;
+        }
+
+        self.y -= 1;
+        self.row = row(self.document, self.y);
+        self.x = self.row.len();
+        true
+    }
+
+    // w
+    fn word_start(&mut self) {
+        let start = self.class();
+        if start != CharClass::Space {
+            while self.class() == start {
+                if !self.forward() {
+                    return;
+                }
+            }
+        } else if self.on_empty_row() && !self.forward() {
+            return;
+        }
+
+        while self.class() == CharClass::Space && !self.on_empty_row() {
+            if !self.forward() {
+                return;
+            }
+        }
+    }
+
+    // e
+    fn word_end(&mut self) {
+        if !self.forward() {
+            return;
+        }
+        while self.class() == CharClass::Space {
+            if !self.forward() {
+                return;
+            }
+        }
+
+        let class = self.class();
+        while self.next_class() == class {
+            self.forward();
+        }
+    }
+
+    // b
+    fn previous_word_start(&mut self) {
+        if !self.backward() {
+            return;
+        }
+        while self.class() == CharClass::Space && !self.on_empty_row() {
+            if !self.backward() {
+                return;
+            }
+        }
+
+        let class = self.class();
+        while self.previous_class() == Some(class) {
+            self.x -= 1;
+        }
+    }
+
+    // ge
+    fn previous_word_end(&mut self) {
+        let start = self.class();
+        if start != CharClass::Space {
+            while self.class() == start {
+                if !self.backward() {
+                    return;
+                }
+            }
+        } else if !self.backward() {
+            return;
+        }
+
+        while self.class() == CharClass::Space && !self.on_empty_row() {
+            if !self.backward() {
+                return;
+            }
+        }
+    }
+}
+
+fn first_non_blank(document: &Document, y: usize) -> usize {
+    let row = row(document, y);
+    (0..row.len())
+        .take_while(|&x| {
+            row.grapheme(x)
+                .is_some_and(|grapheme| grapheme.trim().is_empty())
+        })
+        .count()
+}
+
+// The first bracket from x on the row, and the one matching it, which can be on another row
+fn matching_bracket(document: &Document, x: usize, y: usize) -> Option<Position> {
+    let mut row = row(document, y);
+    let (mut x, same, other, forward) = (cmp::min(x, row.len().saturating_sub(1))..row.len())
+        .find_map(|x| {
+            let grapheme = row.grapheme(x)?;
+            BRACKETS.iter().find_map(|(open, close)| match grapheme {
+                g if g == *open => Some((x, *open, *close, true)),
+                g if g == *close => Some((x, *close, *open, false)),
+                _ => None,
+            })
+        })?;
+
+    let mut y = y;
+    let mut depth = 0;
+    loop {
+        if forward {
+            x += 1;
+            while x >= row.len() {
+                y += 1;
+                if y >= document.len() {
+                    return None;
+                }
+                row = self::row(document, y);
+                x = 0;
+            }
+        } else {
+            while x == 0 {
+                y = y.checked_sub(1)?;
+                row = self::row(document, y);
+                x = row.len();
+            }
+            x -= 1;
+        }
+
+        let grapheme = row.grapheme(x);
+        if grapheme == Some(same) {
+            depth += 1;
+        } else if grapheme == Some(other) {
+            if depth == 0 {
+                return Some(Position::new(x, y));
+            }
+            depth -= 1;
+        }
+    }
+}
+
+// Rows past the end of the document are empty
+fn row(document: &Document, y: usize) -> Rc<Row> {
+    document.row(y).unwrap_or_default()
+}
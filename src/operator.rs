@@ -0,0 +1,13 @@
+// Commands that act on a region of text, a selection or the text covered by a motion
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operator {
+    Delete,
+    Yank,
+    Change,
+    Indent,
+    Outdent,
+    ToggleCase,
+    Lowercase,
+    Uppercase,
+    Join,
+}
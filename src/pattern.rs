@@ -0,0 +1,324 @@
+use regex::{Regex, RegexBuilder};
+use std::str::Chars;
+
+// Compiles a Vim pattern into a regex.
+// Patterns are "magic" like in Vim: ( ) | + ? { are literal unless escaped, \< and \> are word
+// boundaries, \v makes the rest "very magic" (every symbol is special), \c and \C force the case.
+pub fn compile(pattern: &str, ignore_case: bool) -> Result<Regex, String> {
+    let (translated, case) = translate(pattern)?;
+
+    RegexBuilder::new(&translated)
+        .case_insensitive(case.unwrap_or(ignore_case))
+        .build()
+        .map_err(|_| format!("E383: Invalid search string: {pattern}"))
+}
+
+// Whether a pattern has an uppercase letter, for smartcase. Escapes like \S don't count.
+pub fn has_uppercase(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c.is_uppercase() => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+// Any character other than a letter, a digit, \, " or | can delimit a pattern, as in s#a#b#
+pub fn delimiter(chars: &mut Chars) -> Result<char, String> {
+    match chars.next() {
+        Some(c) if c.is_alphanumeric() || matches!(c, '\\' | '"' | '|') => {
+            Err("E146: Regular expressions can't be delimited by letters".to_string())
+        }
+        Some(c) => Ok(c),
+        None => Err("E35: No previous regular expression".to_string()),
+    }
+}
+
+// Text up to an unescaped delimiter or the end, \ before the delimiter is dropped
+pub fn delimited(chars: &mut Chars, delimiter: char) -> String {
+    let mut text = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if c == delimiter => text.push(c),
+                Some(c) => {
+                    text.push('\\');
+                    text.push(c);
+                }
+                None => text.push('\\'),
+            },
+            c if c == delimiter => break,
+            c => text.push(c),
+        }
+    }
+
+    text
+}
+
+// Rust regex syntax of a pattern, and the case forced by \c or \C
+fn translate(pattern: &str) -> Result<(String, Option<bool>), String> {
+    let mut translated = String::new();
+    let mut ignore_case = None;
+    let mut very_magic = false;
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('c') => ignore_case = Some(true),
+                Some('C') => ignore_case = Some(false),
+                Some('v') => very_magic = true,
+                Some('m') => very_magic = false,
+                Some('<' | '>') if !very_magic => translated.push_str(r"\b"),
+                Some('=') if !very_magic => translated.push('?'),
+                Some(c @ ('(' | ')' | '|' | '+' | '?')) if !very_magic => translated.push(c),
+                Some('{') if !very_magic => count(&mut chars, &mut translated)?,
+                // The regex crate has no back-references
+                Some('1'..='9') => {
+                    return Err(format!("Back-references are not supported: {pattern}"));
+                }
+                Some('n') => translated.push_str(r"\n"),
+                Some('t') => translated.push_str(r"\t"),
+                Some('e') => translated.push_str(r"\x1b"),
+                Some('a') => translated.push_str("[a-zA-Z]"),
+                Some('A') => translated.push_str("[^a-zA-Z]"),
+                Some('l') => translated.push_str("[a-z]"),
+                Some('L') => translated.push_str("[^a-z]"),
+                Some('u') => translated.push_str("[A-Z]"),
+                Some('U') => translated.push_str("[^A-Z]"),
+                Some(c @ ('s' | 'S' | 'd' | 'D' | 'w' | 'W')) => {
+                    translated.push('\\');
+                    translated.push(c);
+                }
+                Some(c) => translated.push_str(&regex::escape(&c.to_string())),
+                // A trailing backslash matches itself
+                None => translated.push_str(r"\\"),
+            },
+            '[' => class(&mut chars, &mut translated),
+            '<' | '>' if very_magic => translated.push_str(r"\b"),
+            '=' if very_magic => translated.push('?'),
+            '{' if very_magic => count(&mut chars, &mut translated)?,
+            '(' | ')' | '|' | '+' | '?' if !very_magic => {
+                translated.push_str(&regex::escape(&c.to_string()))
+            }
+            '.' | '*' | '^' | '$' | '(' | ')' | '|' | '+' | '?' => translated.push(c),
+            c => translated.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    Ok((translated, ignore_case))
+}
+
+// The count of \{n,m}, \{n}, \{n,}, \{,m} or \{}, up to } or \}.
+// \{-n,m} matches as few times as it can.
+fn count(chars: &mut Chars, translated: &mut String) -> Result<(), String> {
+    let error = || r"E554: Syntax error in \{...}".to_string();
+
+    let mut count = String::new();
+    loop {
+        match chars.next().ok_or_else(error)? {
+            '}' => break,
+            '\\' if chars.clone().next() == Some('}') => {
+                chars.next();
+                break;
+            }
+            c => count.push(c),
+        }
+    }
+
+    let (lazy, count) = match count.strip_prefix('-') {
+        Some(count) => (true, count),
+        None => (false, count.as_str()),
+    };
+    let (min, max) = match count.split_once(',') {
+        Some((min, max)) => (min, Some(max)),
+        None => (count, None),
+    };
+    let is_number = |text: &str| text.chars().all(|c| c.is_ascii_digit());
+    if !is_number(min) || !max.is_none_or(is_number) {
+        return Err(error());
+    }
+
+    match (min, max) {
+        ("", None | Some("")) => translated.push('*'),
+        (min, None) => translated.push_str(&format!("{{{min}}}")),
+        ("", Some(max)) => translated.push_str(&format!("{{0,{max}}}")),
+        (min, Some(max)) => translated.push_str(&format!("{{{min},{max}}}")),
+    }
+    if lazy {
+        translated.push('?');
+    }
+    Ok(())
+}
+
+// A [] collection, copied up to its closing bracket. A [ without one matches itself.
+fn class(chars: &mut Chars, translated: &mut String) {
+    let mut class = String::from("[");
+    let mut inner = chars.clone().peekable();
+    let mut consumed = 0;
+
+    if inner.peek() == Some(&'^') {
+        class.push('^');
+        inner.next();
+        consumed += 1;
+    }
+    // A ] right after the opening bracket is part of the collection
+    if inner.peek() == Some(&']') {
+        class.push_str(r"\]");
+        inner.next();
+        consumed += 1;
+    }
+
+    let mut closed = false;
+    while let Some(c) = inner.next() {
+        consumed += 1;
+        match c {
+            ']' => {
+                closed = true;
+                break;
+            }
+            '\\' => {
+                if let Some(next) = inner.next() {
+                    consumed += 1;
+                    match next {
+                        'n' => class.push_str(r"\n"),
+                        't' => class.push_str(r"\t"),
+                        next => class.push_str(&regex::escape(&next.to_string())),
+                    }
+                }
+            }
+            // Characters that mean something else in a Rust class
+            '[' | '&' | '~' => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+    }
+
+    if closed {
+        class.push(']');
+        translated.push_str(&class);
+        for _ in 0..consumed {
+            chars.next();
+        }
+    } else {
+        translated.push_str(r"\[");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translated(pattern: &str) -> String {
+        translate(pattern).unwrap().0
+    }
+
+    #[test]
+    fn magic_symbols_are_literal_unless_escaped() {
+        assert_eq!(translated("a.b*"), "a.b*");
+        assert_eq!(translated("^(a)|b+?$"), r"^\(a\)\|b\+\?$");
+        assert_eq!(translated(r"\(a\)\|b\+\?"), "(a)|b+?");
+        assert_eq!(translated(r"a\="), "a?");
+        assert_eq!(translated(r"\<foo\>"), r"\bfoo\b");
+        assert_eq!(translated("<foo>"), "<foo>");
+        assert_eq!(translated("a{2}"), r"a\{2\}");
+    }
+
+    #[test]
+    fn very_magic_makes_every_symbol_special() {
+        assert_eq!(translated(r"\v(a)|b+?"), "(a)|b+?");
+        assert_eq!(translated(r"\v\(a\)"), r"\(a\)");
+        assert_eq!(translated(r"\v<foo>"), r"\bfoo\b");
+        assert_eq!(translated(r"\va="), "a?");
+        assert_eq!(translated(r"\va{2,3}"), "a{2,3}");
+        // \m goes back to magic
+        assert_eq!(translated(r"\v(a)\m(b)"), r"(a)\(b\)");
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(translated(r"a\{2}"), "a{2}");
+        assert_eq!(translated(r"a\{2,3}"), "a{2,3}");
+        assert_eq!(translated(r"a\{2,3\}"), "a{2,3}");
+        assert_eq!(translated(r"a\{2,}"), "a{2,}");
+        assert_eq!(translated(r"a\{,3}"), "a{0,3}");
+        assert_eq!(translated(r"a\{}"), "a*");
+        assert_eq!(translated(r"a\{-1,}"), "a{1,}?");
+        assert_eq!(translated(r"a\{-}"), "a*?");
+        assert_eq!(translated(r"\(ab\)\{2}c"), "(ab){2}c");
+
+        let error = Err(r"E554: Syntax error in \{...}".to_string());
+        assert_eq!(translate(r"a\{x}"), error);
+        assert_eq!(translate(r"a\{2"), error);
+
+        let regex = compile(r"^a\{2,3}$", false).unwrap();
+        assert!(!regex.is_match("a"));
+        assert!(regex.is_match("aaa"));
+        assert!(!regex.is_match("aaaa"));
+    }
+
+    #[test]
+    fn back_references_are_an_error() {
+        assert_eq!(
+            translate(r"\(a\)\1"),
+            Err(r"Back-references are not supported: \(a\)\1".to_string())
+        );
+        assert!(compile(r"\v(a)\1", false).is_err());
+    }
+
+    #[test]
+    fn escapes_and_classes() {
+        assert_eq!(translated(r"\t\n\e"), r"\t\n\x1b");
+        assert_eq!(translated(r"\s\S\d\w"), r"\s\S\d\w");
+        assert_eq!(translated(r"\a\l\u"), "[a-zA-Z][a-z][A-Z]");
+        assert_eq!(translated(r"\/\."), r"/\.");
+        assert_eq!(translated("a\\"), r"a\\");
+        assert_eq!(translated("[a-z]x"), "[a-z]x");
+        assert_eq!(translated("[^]x]"), r"[^\]x]");
+        assert_eq!(translated("[&~]"), r"[\&\~]");
+        assert_eq!(translated(r"[\t]"), r"[\t]");
+        // A [ that isn't closed matches itself
+        assert_eq!(translated("a[b"), r"a\[b");
+    }
+
+    #[test]
+    fn case() {
+        assert_eq!(translate(r"\cfoo"), Ok(("foo".to_string(), Some(true))));
+        assert_eq!(translate(r"foo\C"), Ok(("foo".to_string(), Some(false))));
+        assert_eq!(translate("foo"), Ok(("foo".to_string(), None)));
+
+        assert!(compile("foo", true).unwrap().is_match("FOO"));
+        assert!(!compile(r"\Cfoo", true).unwrap().is_match("FOO"));
+        assert!(compile(r"\cfoo", false).unwrap().is_match("FOO"));
+
+        assert!(!has_uppercase("foo"));
+        assert!(has_uppercase("Foo"));
+        assert!(!has_uppercase(r"\Sfoo\W"));
+    }
+
+    #[test]
+    fn delimiters() {
+        assert_eq!(delimiter(&mut "#a#".chars()), Ok('#'));
+        assert_eq!(
+            delimiter(&mut "a".chars()),
+            Err("E146: Regular expressions can't be delimited by letters".to_string())
+        );
+        assert_eq!(
+            delimiter(&mut "".chars()),
+            Err("E35: No previous regular expression".to_string())
+        );
+
+        let mut chars = r"a\/b\.c/rest".chars();
+        assert_eq!(delimited(&mut chars, '/'), r"a/b\.c");
+        assert_eq!(chars.as_str(), "rest");
+        assert_eq!(delimited(&mut "no end".chars(), '/'), "no end");
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Clone)]
+use std::cmp::Ordering;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -15,3 +17,16 @@ impl Default for Position {
         Self::new(0, 0)
     }
 }
+
+// Positions are ordered like the text they point to, row first
+impl Ord for Position {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.y, self.x).cmp(&(other.y, other.x))
+    }
+}
+
+impl PartialOrd for Position {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
@@ -0,0 +1,229 @@
+use crate::clipboard::{Clipboard, ClipboardSelection};
+use crate::selection::SelectionKind;
+use std::collections::HashMap;
+use std::io;
+
+const NUMBERED: usize = 10;
+
+// Text that was yanked or deleted, with the kind of selection it came from
+#[derive(Clone)]
+pub struct Register {
+    pub text: String,
+    pub kind: SelectionKind,
+}
+
+impl Default for Register {
+    fn default() -> Self {
+        Self {
+            text: String::new(),
+            kind: SelectionKind::Charwise,
+        }
+    }
+}
+
+impl Register {
+    fn append(&mut self, other: Register) {
+        // Appending lines to text, or text to lines, makes lines
+        if self.kind == SelectionKind::Linewise || other.kind == SelectionKind::Linewise {
+            if !self.text.is_empty() && !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.text.push_str(&other.text);
+            if !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.kind = SelectionKind::Linewise;
+        } else {
+            self.text.push_str(&other.text);
+        }
+    }
+}
+
+// Vim style registers: the unnamed one ("), "a to "z (appended to through "A to "Z),
+// "0 with the last yank, "1 to "9 with the last deletes of whole lines, "- with the last
+// delete within a line, the black hole "_, and the system clipboard "+ and primary selection "*
+pub struct Registers {
+    unnamed: Register,
+    named: HashMap<char, Register>,
+    // Index 0 is the last yank, the others the deletes from newest to oldest
+    numbered: Vec<Register>,
+    small_delete: Register,
+    clipboard: Box<dyn Clipboard>,
+}
+
+impl Registers {
+    pub fn new(clipboard: Box<dyn Clipboard>) -> Self {
+        Self {
+            unnamed: Register::default(),
+            named: HashMap::new(),
+            numbered: vec![Register::default(); NUMBERED],
+            small_delete: Register::default(),
+            clipboard,
+        }
+    }
+
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '_' | '+' | '*')
+    }
+
+    pub fn get(&mut self, name: Option<char>) -> io::Result<Option<Register>> {
+        let register = match name {
+            None | Some('"') => Some(self.unnamed.clone()),
+            Some('-') => Some(self.small_delete.clone()),
+            Some(name) if name.is_ascii_digit() => {
+                Some(self.numbered[name.to_digit(10).unwrap_or(0) as usize].clone())
+            }
+            Some(name) if name.is_ascii_alphabetic() => {
+                self.named.get(&name.to_ascii_lowercase()).cloned()
+            }
+            Some(name) => match clipboard_selection(name) {
+                Some(selection) => self.paste(name, selection)?,
+                None => None,
+            },
+        };
+
+        Ok(register.filter(|register| !register.text.is_empty()))
+    }
+
+    pub fn yank(&mut self, name: Option<char>, register: Register) -> io::Result<()> {
+        if name.is_none() || name == Some('"') {
+            self.numbered[0] = register.clone();
+        }
+        self.store(name, register)
+    }
+
+    pub fn delete(&mut self, name: Option<char>, register: Register) -> io::Result<()> {
+        if name.is_none() || name == Some('"') {
+            if register.text.contains('\n') {
+                self.numbered.pop();
+                self.numbered.insert(1, register.clone());
+            } else {
+                self.small_delete = register.clone();
+            }
+        }
+        self.store(name, register)
+    }
+
+    // Macros only go to their register, the unnamed register keeps the last yank or delete
+    pub fn record(&mut self, name: char, register: Register) -> io::Result<()> {
+        let unnamed = self.unnamed.clone();
+        self.store(Some(name), register)?;
+        if name != '"' {
+            self.unnamed = unnamed;
+        }
+        Ok(())
+    }
+
+    // Reads the clipboard, falling back to what was last copied when it can't be read
+    fn paste(&mut self, name: char, selection: ClipboardSelection) -> io::Result<Option<Register>> {
+        let copied = self.named.get(&name).cloned();
+
+        let register = match self.clipboard.paste(selection)? {
+            // Copied from here, keep the kind of selection it came from
+            Some(text) if copied.as_ref().is_some_and(|copied| copied.text == text) => copied,
+            Some(text) => {
+                let kind = if text.ends_with('\n') {
+                    SelectionKind::Linewise
+                } else {
+                    SelectionKind::Charwise
+                };
+                Some(Register { text, kind })
+            }
+            None => copied,
+        };
+
+        Ok(register)
+    }
+
+    // Writes a register, the unnamed register always holds what was written last
+    fn store(&mut self, name: Option<char>, register: Register) -> io::Result<()> {
+        match name {
+            Some('_') => return Ok(()),
+            Some(name) if name.is_ascii_uppercase() => {
+                let named = self.named.entry(name.to_ascii_lowercase()).or_default();
+                named.append(register);
+                self.unnamed = named.clone();
+                return Ok(());
+            }
+            Some(name) if name.is_ascii_lowercase() => {
+                self.named.insert(name, register.clone());
+            }
+            Some(name) if name.is_ascii_digit() => {
+                self.numbered[name.to_digit(10).unwrap_or(0) as usize] = register.clone();
+            }
+            Some('-') => self.small_delete = register.clone(),
+            Some(name) => {
+                if let Some(selection) = clipboard_selection(name) {
+                    self.named.insert(name, register.clone());
+                    self.unnamed = register.clone();
+                    return self.clipboard.copy(selection, &register.text);
+                }
+            }
+            None => {}
+        }
+
+        self.unnamed = register;
+        Ok(())
+    }
+}
+
+fn clipboard_selection(name: char) -> Option<ClipboardSelection> {
+    match name {
+        '+' => Some(ClipboardSelection::Clipboard),
+        '*' => Some(ClipboardSelection::Primary),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::{self, ClipboardProvider};
+
+    fn registers() -> Registers {
+        Registers::new(clipboard::new(ClipboardProvider::Fake))
+    }
+
+    fn register(text: &str, kind: SelectionKind) -> Register {
+        Register {
+            text: text.to_string(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn yank_to_clipboard_keeps_the_selection_kind() {
+        let mut registers = registers();
+        registers
+            .yank(Some('+'), register("line\n", SelectionKind::Linewise))
+            .unwrap();
+
+        let put = registers.get(Some('+')).unwrap().unwrap();
+        assert_eq!(put.text, "line\n");
+        assert!(put.kind == SelectionKind::Linewise);
+    }
+
+    #[test]
+    fn clipboard_and_primary_selection_are_separate() {
+        let mut registers = registers();
+        registers
+            .yank(Some('*'), register("primary", SelectionKind::Charwise))
+            .unwrap();
+
+        assert!(registers.get(Some('+')).unwrap().is_none());
+        assert_eq!(registers.get(Some('*')).unwrap().unwrap().text, "primary");
+    }
+
+    #[test]
+    fn delete_to_clipboard_is_put_from_the_unnamed_register() {
+        let mut registers = registers();
+        registers
+            .delete(Some('+'), register("word", SelectionKind::Charwise))
+            .unwrap();
+
+        assert_eq!(registers.get(None).unwrap().unwrap().text, "word");
+        assert_eq!(registers.get(Some('+')).unwrap().unwrap().text, "word");
+        // Only deletes without a register shift the numbered ones
+        assert!(registers.get(Some('1')).unwrap().is_none());
+    }
+}
@@ -1,35 +1,40 @@
 use crate::SearchDirection;
+use regex::Regex;
+use std::cmp;
+use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
 
-#[derive(Default)]
+// A read-only view of a document line, with the byte offset of every grapheme cached
+#[derive(Clone, Default)]
 pub struct Row {
     string: String,
-    len: usize,
+    graphemes: Vec<usize>,
 }
 
 impl From<&str> for Row {
     fn from(slice: &str) -> Self {
         Self {
             string: String::from(slice),
-            len: slice.graphemes(true).count(),
+            graphemes: slice
+                .grapheme_indices(true)
+                .map(|(index, _)| index)
+                .collect(),
         }
     }
 }
 
 impl Row {
     pub fn get_display_graphemes(&self, start: usize, end: usize) -> String {
-        let mut result = String::new();
-
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index >= start && index < end {
-                result.push_str(grapheme);
-            }
+        let end = cmp::min(end, self.len());
+        if start >= end {
+            return String::new();
         }
-        result
+
+        self.string[self.byte_index(start)..self.byte_index(end)].to_string()
     }
 
-    pub fn as_bytes(&self) -> &[u8] {
-        self.string.as_bytes()
+    pub fn as_str(&self) -> &str {
+        &self.string
     }
 
     pub fn is_empty(&self) -> bool {
@@ -37,141 +42,69 @@ impl Row {
     }
 
     pub fn len(&self) -> usize {
-        self.len
+        self.graphemes.len()
     }
 
-    pub fn insert(&mut self, at: usize, c: char) {
-        // End of line, just append
-        if at >= self.len {
-            self.string.push(c);
-            self.len += 1;
-        } else {
-            // Not end of line, divide and append in the correct position in the middle
-            let mut result: String = String::new();
-            let mut length = 0;
-            for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-                length += 1;
-
-                if index == at {
-                    length += 1;
-                    result.push(c);
-                }
-
-                result.push_str(grapheme);
-            }
-
-            self.len = length;
-            self.string = result;
+    pub fn grapheme(&self, at: usize) -> Option<&str> {
+        if at >= self.len() {
+            return None;
         }
+        Some(&self.string[self.byte_index(at)..self.byte_index(at + 1)])
     }
 
-    pub fn delete(&mut self, at: usize) {
-        // End of line, do nothing
-        if at > self.len {
-            return;
-        }
-        let mut result: String = String::new();
-        let mut length = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index != at {
-                length += 1;
-                result.push_str(grapheme);
-            }
-        }
-        self.len = length;
-        self.string = result;
+    // Number of chars before the grapheme at the given index
+    pub fn char_offset(&self, at: usize) -> usize {
+        self.string[..self.byte_index(at)].chars().count()
     }
 
-    pub fn append(&mut self, new: &Self) {
-        self.string = format!("{}{}", self.string, new.string);
-        self.len += new.len;
+    fn byte_index(&self, at: usize) -> usize {
+        self.graphemes.get(at).copied().unwrap_or(self.string.len())
     }
 
-    pub fn split(&mut self, at: usize) -> Self {
-        let mut row: String = String::new();
-        let mut length = 0;
-        let mut splitted_row: String = String::new();
-        let mut splitted_length = 0;
-
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index < at {
-                length += 1;
-                row.push_str(grapheme);
-            } else {
-                splitted_length += 1;
-                splitted_row.push_str(grapheme);
+    // Column of the first match from `at` on, or of the last match starting before `at`.
+    // Searching backward from past the end of the row takes every match.
+    pub fn find(&self, regex: &Regex, at: usize, direction: SearchDirection) -> Option<usize> {
+        let start = match direction {
+            SearchDirection::Forward if at > self.len() => return None,
+            SearchDirection::Forward => regex.find_at(&self.string, self.byte_index(at))?.start(),
+            SearchDirection::Backward => {
+                let end = if at > self.len() {
+                    self.string.len() + 1
+                } else {
+                    self.byte_index(at)
+                };
+                regex
+                    .find_iter(&self.string)
+                    .take_while(|found| found.start() < end)
+                    .last()?
+                    .start()
             }
-        }
-
-        self.string = row;
-        self.len = length;
-
-        Self {
-            string: splitted_row,
-            len: splitted_length,
-        }
-    }
-
-    pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
-        if at > self.len || query.is_empty() {
-            return None;
-        }
-
-        // If forward search, search from current pos to end of line
-        // Else search from start of line to current pos
-        let start = if direction == SearchDirection::Forward {
-            at
-        } else {
-            0
-        };
-        let end = if direction == SearchDirection::Forward {
-            self.len
-        } else {
-            at
         };
 
-        let substring: String = self.string[..]
-            .graphemes(true)
-            .skip(start)
-            .take(end - start)
-            .collect();
+        Some(self.column(start))
+    }
 
-        let matching_byte_index = if direction == SearchDirection::Forward {
-            substring.find(query)
-        } else {
-            substring.rfind(query)
-        };
+    // Columns of every match, to highlight them
+    pub fn matches(&self, regex: &Regex) -> Vec<Range<usize>> {
+        regex
+            .find_iter(&self.string)
+            .filter(|found| !found.is_empty())
+            .map(|found| self.column(found.start())..self.column(found.end()))
+            .collect()
+    }
 
-        if let Some(matching_byte_index) = matching_byte_index {
-            for (grapheme_index, (byte_index, _)) in
-                substring[..].grapheme_indices(true).enumerate()
-            {
-                if matching_byte_index == byte_index {
-                    return Some(start + grapheme_index);
-                }
-            }
-        }
-        None
+    // Grapheme at a byte index, or the next one when the index is inside a grapheme
+    pub fn column(&self, byte_index: usize) -> usize {
+        self.graphemes.partition_point(|&index| index < byte_index)
     }
 
-    fn highlight_match(&mut self, word: Option<&str>) {
-        // if let Some(word) = word {
-        //     if word.is_empty() {
-        //         return;
-        //     }
-        //     let mut index = 0;
-        //     while let Some(search_match) = self.find(word, index, SearchDirection::Forward) {
-        //         if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count())
-        //         {
-        //             #[allow(clippy::indexing_slicing)]
-        //             for i in index.saturating_add(search_match)..next_index {
-        //                 self.highlighting[i] = highlighting::Type::Match;
-        //             }
-        //             index = next_index;
-        //         } else {
-        //             break;
-        //         }
-        //     }
-        // }
+    // Grapheme at a byte index, or the one the index is inside of
+    pub fn containing_column(&self, byte_index: usize) -> usize {
+        if byte_index >= self.string.len() {
+            return self.len();
+        }
+        self.graphemes
+            .partition_point(|&index| index <= byte_index)
+            .saturating_sub(1)
     }
 }
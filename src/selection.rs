@@ -0,0 +1,141 @@
+use crate::{Document, Position};
+use std::cmp;
+use std::ops::Range;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SelectionKind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+// A region of a document. Charwise selections go from start to end (exclusive),
+// linewise ones cover the rows from start to end, and blockwise ones the columns
+// from start to end (exclusive) on each of those rows.
+#[derive(Clone)]
+pub struct Selection {
+    pub start: Position,
+    pub end: Position,
+    pub kind: SelectionKind,
+}
+
+impl Selection {
+    // Region between the visual mode anchor and the cursor, both included
+    pub fn from_visual(
+        anchor: &Position,
+        cursor: &Position,
+        kind: SelectionKind,
+        document: &Document,
+    ) -> Self {
+        let last_row = document.len().saturating_sub(1);
+        let anchor = Position::new(anchor.x, cmp::min(anchor.y, last_row));
+        let cursor = Position::new(cursor.x, cmp::min(cursor.y, last_row));
+        let (first, last) = if anchor <= cursor {
+            (anchor, cursor)
+        } else {
+            (cursor, anchor)
+        };
+
+        match kind {
+            SelectionKind::Charwise => {
+                let width = document.row_len(last.y);
+                // Selecting past the end of a row includes its line break
+                let end = if last.x < width {
+                    Position::new(last.x + 1, last.y)
+                } else if last.y < last_row {
+                    Position::new(0, last.y + 1)
+                } else {
+                    Position::new(width, last.y)
+                };
+
+                Self {
+                    start: first,
+                    end,
+                    kind,
+                }
+            }
+            SelectionKind::Linewise => Self {
+                start: Position::new(0, first.y),
+                end: Position::new(0, last.y),
+                kind,
+            },
+            SelectionKind::Blockwise => Self {
+                start: Position::new(cmp::min(first.x, last.x), first.y),
+                end: Position::new(cmp::max(first.x, last.x) + 1, last.y),
+                kind,
+            },
+        }
+    }
+
+    pub fn first_row(&self) -> usize {
+        self.start.y
+    }
+
+    pub fn last_row(&self) -> usize {
+        match self.kind {
+            // A charwise selection ending at the start of a row doesn't include it
+            SelectionKind::Charwise if self.end.x == 0 && self.end.y > self.start.y => {
+                self.end.y - 1
+            }
+            _ => self.end.y,
+        }
+    }
+
+    // Selected columns of a row, one past the end of the row when the line break is selected
+    pub fn columns(&self, y: usize, width: usize) -> Option<Range<usize>> {
+        if y < self.start.y || y > self.last_row() {
+            return None;
+        }
+
+        match self.kind {
+            SelectionKind::Charwise => {
+                let start = if y == self.start.y { self.start.x } else { 0 };
+                let end = if y == self.end.y {
+                    self.end.x
+                } else {
+                    width + 1
+                };
+                Some(start..end)
+            }
+            SelectionKind::Linewise => Some(0..width + 1),
+            SelectionKind::Blockwise => {
+                Some(cmp::min(self.start.x, width)..cmp::min(self.end.x, width))
+            }
+        }
+    }
+
+    // Text ranges covered by the selection, a single one except for blocks.
+    // Linewise ranges don't include the line break of the last row.
+    pub fn ranges(&self, document: &Document) -> Vec<(Position, Position)> {
+        match self.kind {
+            SelectionKind::Charwise => vec![(self.start.clone(), self.end.clone())],
+            SelectionKind::Linewise => vec![(
+                Position::new(0, self.start.y),
+                Position::new(document.row_len(self.end.y), self.end.y),
+            )],
+            SelectionKind::Blockwise => (self.start.y..=self.end.y)
+                .map(|y| {
+                    let width = document.row_len(y);
+                    (
+                        Position::new(cmp::min(self.start.x, width), y),
+                        Position::new(cmp::min(self.end.x, width), y),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // Selected text, as it would be put back
+    pub fn text(&self, document: &Document) -> String {
+        let pieces: Vec<String> = self
+            .ranges(document)
+            .iter()
+            .map(|(start, end)| document.text_range(start, end))
+            .collect();
+
+        match self.kind {
+            SelectionKind::Linewise => format!("{}\n", pieces.join("\n")),
+            _ => pieces.join("\n"),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::clipboard::ClipboardProvider;
 use anyhow::Result;
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
@@ -9,6 +10,17 @@ pub struct EditorConfig {
     pub indent_size: u8,
     pub tab_size: u8,
     pub line_numbers: String,
+    // Keep a `file~` copy of the previous contents when saving
+    #[serde(default)]
+    pub backup: bool,
+    // How "+ and "* reach the system clipboard: auto, osc52, wl-copy, xclip or fake
+    #[serde(default)]
+    pub clipboard: ClipboardProvider,
+    // Searches ignore case, unless smart_case is set and the pattern has an uppercase letter
+    #[serde(default)]
+    pub ignore_case: bool,
+    #[serde(default)]
+    pub smart_case: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -62,6 +74,10 @@ impl Default for Settings {
                 indent_size: 4,
                 tab_size: 4,
                 line_numbers: "relative".to_string(),
+                backup: false,
+                clipboard: ClipboardProvider::Auto,
+                ignore_case: false,
+                smart_case: false,
             },
             appearance: AppearanceConfig {
                 theme: "base16-ocean.dark".to_string(),
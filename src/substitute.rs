@@ -0,0 +1,172 @@
+use crate::pattern;
+use regex::Captures;
+
+#[derive(Clone, Default)]
+pub struct Flags {
+    // Every match of a line instead of the first one
+    pub global: bool,
+    // Ask before each substitution
+    pub confirm: bool,
+    // i or I override the case of the pattern
+    pub ignore_case: Option<bool>,
+    // Only count the matches
+    pub count_only: bool,
+}
+
+// The argument of :s/pattern/replacement/flags
+#[derive(Clone)]
+pub struct Substitute {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: Flags,
+}
+
+impl Substitute {
+    // The pattern and the replacement end with the delimiter, which is the first character
+    pub fn parse(argument: &str) -> Result<Self, String> {
+        let mut chars = argument.chars();
+        let delimiter = pattern::delimiter(&mut chars)?;
+        let pattern = pattern::delimited(&mut chars, delimiter);
+        let replacement = pattern::delimited(&mut chars, delimiter);
+
+        let mut flags = Flags::default();
+        while let Some(c) = chars.next() {
+            match c {
+                'g' => flags.global = !flags.global,
+                'c' => flags.confirm = true,
+                'i' => flags.ignore_case = Some(true),
+                'I' => flags.ignore_case = Some(false),
+                'n' => flags.count_only = true,
+                c if c.is_whitespace() => (),
+                _ => {
+                    return Err(format!("E488: Trailing characters: {c}{}", chars.as_str()));
+                }
+            }
+        }
+
+        Ok(Self {
+            pattern,
+            replacement,
+            flags,
+        })
+    }
+
+    // Replacement for a match: & and \0 are the whole match, \1 to \9 its groups,
+    // \r and \n break the line, \t is a tab and \ makes the next character literal
+    pub fn expand(&self, captures: &Captures) -> String {
+        let group = |index: usize| captures.get(index).map_or("", |group| group.as_str());
+        let mut expanded = String::new();
+        let mut chars = self.replacement.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '&' => expanded.push_str(group(0)),
+                '\\' => match chars.next() {
+                    Some(digit @ '0'..='9') => {
+                        expanded.push_str(group(digit as usize - '0' as usize))
+                    }
+                    Some('r' | 'n') => expanded.push('\n'),
+                    Some('t') => expanded.push('\t'),
+                    Some(c) => expanded.push(c),
+                    None => expanded.push('\\'),
+                },
+                c => expanded.push(c),
+            }
+        }
+
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn parsed(argument: &str) -> Substitute {
+        Substitute::parse(argument).unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    fn expanded(replacement: &str, regex: &str, text: &str) -> String {
+        let substitute = Substitute {
+            pattern: regex.to_string(),
+            replacement: replacement.to_string(),
+            flags: Flags::default(),
+        };
+        let regex = Regex::new(regex).unwrap();
+        substitute.expand(&regex.captures(text).unwrap())
+    }
+
+    #[test]
+    fn pattern_replacement_and_flags() {
+        let substitute = parsed("/old/new/gci");
+        assert_eq!(substitute.pattern, "old");
+        assert_eq!(substitute.replacement, "new");
+        assert!(substitute.flags.global);
+        assert!(substitute.flags.confirm);
+        assert_eq!(substitute.flags.ignore_case, Some(true));
+        assert!(!substitute.flags.count_only);
+
+        let substitute = parsed("/a/b/I n");
+        assert_eq!(substitute.flags.ignore_case, Some(false));
+        assert!(substitute.flags.count_only);
+        // gg turns global off again
+        assert!(!parsed("/a/b/gg").flags.global);
+
+        assert!(matches!(
+            Substitute::parse("/a/b/x"),
+            Err(error) if error == "E488: Trailing characters: x"
+        ));
+    }
+
+    #[test]
+    fn missing_parts_are_empty() {
+        let substitute = parsed("/old");
+        assert_eq!(substitute.pattern, "old");
+        assert_eq!(substitute.replacement, "");
+
+        let substitute = parsed("//new/");
+        assert_eq!(substitute.pattern, "");
+        assert_eq!(substitute.replacement, "new");
+    }
+
+    #[test]
+    fn other_delimiters_and_escapes() {
+        let substitute = parsed("#a/b#c/d#g");
+        assert_eq!(substitute.pattern, "a/b");
+        assert_eq!(substitute.replacement, "c/d");
+        assert!(substitute.flags.global);
+
+        let substitute = parsed(r"/a\/b/c\/d/");
+        assert_eq!(substitute.pattern, "a/b");
+        assert_eq!(substitute.replacement, "c/d");
+
+        // Other escapes are kept for the pattern and the replacement
+        let substitute = parsed(r"/a\.b/\&/");
+        assert_eq!(substitute.pattern, r"a\.b");
+        assert_eq!(substitute.replacement, r"\&");
+
+        assert!(matches!(
+            Substitute::parse("a/b/"),
+            Err(error) if error == "E146: Regular expressions can't be delimited by letters"
+        ));
+    }
+
+    #[test]
+    fn expand_matches_and_groups() {
+        assert_eq!(expanded("[&]", "b+", "abbc"), "[bb]");
+        assert_eq!(expanded(r"<\0>", "b+", "abbc"), "<bb>");
+        assert_eq!(expanded(r"\2-\1", r"(\w+) (\w+)", "one two"), "two-one");
+        // Groups that didn't take part in the match are empty
+        assert_eq!(expanded(r"[\1\3]", r"(a)|(b)", "a"), "[a]");
+    }
+
+    #[test]
+    fn expand_escapes() {
+        assert_eq!(expanded(r"\&", "b", "b"), "&");
+        assert_eq!(expanded(r"\\", "b", "b"), r"\");
+        assert_eq!(expanded(r"a\rb\nc", "b", "b"), "a\nb\nc");
+        assert_eq!(expanded(r"\t", "b", "b"), "\t");
+        assert_eq!(expanded("end\\", "b", "b"), "end\\");
+    }
+}
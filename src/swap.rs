@@ -0,0 +1,57 @@
+use crate::utils;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The swap file a session writes the unsaved changes of a document to
+#[derive(Default)]
+pub enum SwapFile {
+    // The one next to the file, from path()
+    #[default]
+    Usual,
+    // Another session has the usual one
+    Other(PathBuf),
+    // Other sessions have every name
+    Disabled,
+}
+
+// Swap files live next to the file they belong to, as `.name.swp`
+pub fn path(file_name: &str) -> PathBuf {
+    let path = Path::new(file_name);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.swp", name))
+}
+
+// When another session has the swap file, the next free name of .swo, .swn and so on down to .swa
+pub fn free_path(file_name: &str) -> Option<PathBuf> {
+    let path = path(file_name);
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let stem = name.strip_suffix('p')?;
+
+    ('a'..='o')
+        .rev()
+        .map(|letter| path.with_file_name(format!("{stem}{letter}")))
+        .find(|path| !path.exists())
+}
+
+pub fn exists(file_name: &str) -> bool {
+    path(file_name).exists()
+}
+
+pub fn write(path: &Path, contents: &str) -> io::Result<()> {
+    utils::write_atomically(&path.to_string_lossy(), false, |file| {
+        file.write_all(contents.as_bytes())
+    })
+}
+
+pub fn read(file_name: &str) -> io::Result<String> {
+    fs::read_to_string(path(file_name))
+}
+
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
@@ -2,7 +2,7 @@ use crate::Position;
 use crossterm::{
     cursor::{self, MoveTo, SetCursorStyle},
     event::{
-        read, DisableMouseCapture, EnableMouseCapture,
+        poll, read, DisableMouseCapture, EnableMouseCapture,
         Event::{Key, Mouse},
         KeyEvent, MouseEventKind,
     },
@@ -11,6 +11,7 @@ use crossterm::{
     Command,
 };
 use std::io::{self, stdout, Write};
+use std::time::Duration;
 
 pub struct Size {
     pub width: u16,
@@ -26,10 +27,11 @@ impl Terminal {
         Terminal::change_defaults();
         let size = terminal::size()?;
 
+        // Leave the last line for the message bar
         Ok(Self {
             size: Size {
                 width: size.0,
-                height: size.1.saturating_sub(2),
+                height: size.1.saturating_sub(1),
             },
         })
     }
@@ -70,6 +72,16 @@ impl Terminal {
         }
     }
 
+    // A key that was already pressed, without waiting for one
+    pub fn poll_key() -> Result<Option<KeyEvent>, std::io::Error> {
+        while poll(Duration::ZERO)? {
+            if let Key(event) = read()? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn size(&self) -> &Size {
         &self.size
     }
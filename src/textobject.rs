@@ -0,0 +1,657 @@
+use crate::keymaps::TextObject;
+use crate::motion::{class, CharClass};
+use crate::selection::{Selection, SelectionKind};
+use crate::{Document, Position, Row};
+use std::cmp;
+
+const LINE_BREAK: &str = "\n";
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// The graphemes of a document, rows separated by a line break. They are read one at a time
+// from the rows, so walking out from the cursor doesn't go through the rest of the document.
+struct Graphemes<'a> {
+    document: &'a Document,
+}
+
+impl<'a> Graphemes<'a> {
+    // The line break after a row is at the column after its last grapheme
+    fn get(&self, at: &Position) -> Option<String> {
+        let row = self.document.row(at.y)?;
+        match row.grapheme(at.x) {
+            Some(grapheme) => Some(grapheme.to_string()),
+            None if at.x == row.len() && at.y + 1 < self.document.len() => {
+                Some(LINE_BREAK.to_string())
+            }
+            None => None,
+        }
+    }
+
+    fn is(&self, at: &Position, grapheme: &str) -> bool {
+        self.get(at).is_some_and(|found| found == grapheme)
+    }
+
+    fn is_blank(&self, at: &Position) -> bool {
+        self.get(at)
+            .is_some_and(|grapheme| grapheme.trim().is_empty())
+    }
+
+    // Position of the next grapheme, the one past the end of the document included
+    fn next(&self, at: &Position) -> Option<Position> {
+        if at.x < self.document.row_len(at.y) {
+            Some(Position::new(at.x + 1, at.y))
+        } else if at.y + 1 < self.document.len() {
+            Some(Position::new(0, at.y + 1))
+        } else {
+            None
+        }
+    }
+
+    fn previous(&self, at: &Position) -> Option<Position> {
+        if at.x > 0 {
+            Some(Position::new(at.x - 1, at.y))
+        } else if at.y > 0 {
+            Some(Position::new(self.document.row_len(at.y - 1), at.y - 1))
+        } else {
+            None
+        }
+    }
+}
+
+// Selection of the graphemes from start up to end (exclusive)
+fn charwise(start: Position, end: Position) -> Option<Selection> {
+    if start > end {
+        return None;
+    }
+
+    Some(Selection {
+        start,
+        end,
+        kind: SelectionKind::Charwise,
+    })
+}
+
+// Text covered by a text object around the cursor, the count selects more of them
+// or, for pairs like brackets, the pair enclosing the one around the cursor
+pub fn selection(
+    document: &Document,
+    at: &Position,
+    object: TextObject,
+    inner: bool,
+    count: usize,
+) -> Option<Selection> {
+    if let TextObject::Paragraph = object {
+        return paragraph(document, at.y, inner, count);
+    }
+
+    // The cursor can be past the end of its row, after $ for instance
+    let at = Position::new(
+        cmp::min(at.x, document.row_len(at.y).saturating_sub(1)),
+        at.y,
+    );
+    let graphemes = Graphemes { document };
+
+    match object {
+        TextObject::Word | TextObject::BigWord => word(
+            document.row(at.y).as_deref()?,
+            &at,
+            object == TextObject::BigWord,
+            inner,
+            count,
+        ),
+        TextObject::Sentence => sentence(&graphemes, &at, inner, count),
+        TextObject::Quote(quote) => self::quote(document.row(at.y).as_deref()?, &at, quote, inner),
+        TextObject::Bracket(open, close) => bracket(&graphemes, &at, open, close, inner, count),
+        TextObject::Tag => tag(&graphemes, &at, inner, count),
+        TextObject::Paragraph => None,
+    }
+}
+
+// iw, aw, iW and aW stay on the cursor row
+fn word(row: &Row, at: &Position, big: bool, inner: bool, count: usize) -> Option<Selection> {
+    let class_at = |x: usize| row.grapheme(x).map(|grapheme| class(grapheme, big));
+    // End (exclusive) of the run of graphemes of the same class starting at x
+    let run_end = |mut x: usize| {
+        let class = class_at(x);
+        while class.is_some() && class_at(x) == class {
+            x += 1;
+        }
+        x
+    };
+    let selection =
+        |start: usize, end: usize| charwise(Position::new(start, at.y), Position::new(end, at.y));
+
+    let class = class_at(at.x)?;
+    let mut start = at.x;
+    while start > 0 && class_at(start - 1) == Some(class) {
+        start -= 1;
+    }
+    let mut end = run_end(at.x);
+
+    if inner {
+        // Blanks between words count as a word too
+        for _ in 1..count {
+            end = run_end(end);
+        }
+        return selection(start, end);
+    }
+
+    let on_space = class == CharClass::Space;
+    for index in 0..count {
+        if index > 0 {
+            end = run_end(end);
+        }
+        // Blanks and the word after them, or the word and the blanks after it
+        if on_space || class_at(end) == Some(CharClass::Space) {
+            end = run_end(end);
+        }
+    }
+
+    // No blank after the words, take the one before them
+    if !on_space && class_at(end - 1) != Some(CharClass::Space) {
+        while start > 0 && class_at(start - 1) == Some(CharClass::Space) {
+            start -= 1;
+        }
+    }
+
+    selection(start, end)
+}
+
+// is and as work within the paragraph around the cursor
+fn sentence(
+    graphemes: &Graphemes,
+    cursor: &Position,
+    inner: bool,
+    count: usize,
+) -> Option<Selection> {
+    let is_empty_row = |at: &Position| at.x == 0 && graphemes.document.row_len(at.y) == 0;
+    if is_empty_row(cursor) {
+        return None;
+    }
+
+    // The paragraph is between empty rows
+    let mut first = cursor.clone();
+    while let Some(previous) = graphemes.previous(&first).filter(|at| !is_empty_row(at)) {
+        first = previous;
+    }
+    let mut last = cursor.clone();
+    while !is_empty_row(&last) {
+        match graphemes.next(&last) {
+            Some(next) => last = next,
+            None => break,
+        }
+    }
+    // Leave out the line break that ends the paragraph
+    if let Some(previous) = graphemes.previous(&last) {
+        if previous >= first && graphemes.is(&previous, LINE_BREAK) {
+            last = previous;
+        }
+    }
+
+    // Sentences start after a ., ! or ? followed by closing characters and a blank
+    let mut starts = vec![first.clone()];
+    let mut at = first.clone();
+    while at < last {
+        if matches!(graphemes.get(&at).as_deref(), Some("." | "!" | "?")) {
+            let mut end = graphemes.next(&at)?;
+            while matches!(graphemes.get(&end).as_deref(), Some(")" | "]" | "\"" | "'")) {
+                end = graphemes.next(&end)?;
+            }
+            if end >= last || graphemes.is_blank(&end) {
+                while end < last && graphemes.is_blank(&end) {
+                    end = graphemes.next(&end)?;
+                }
+                if end < last {
+                    starts.push(end.clone());
+                }
+                at = end;
+                continue;
+            }
+        }
+        at = graphemes.next(&at)?;
+    }
+    starts.push(last.clone());
+
+    let sentence = starts.windows(2).position(|window| *cursor < window[1])?;
+    let mut start = starts[sentence].clone();
+    let next = cmp::min(sentence + count, starts.len() - 1);
+    let mut end = starts[next].clone();
+
+    if inner {
+        // Trailing blanks are left out, unless the cursor is on them
+        let mut text_end = end.clone();
+        while let Some(previous) = graphemes
+            .previous(&text_end)
+            .filter(|previous| *previous >= start && graphemes.is_blank(previous))
+        {
+            text_end = previous;
+        }
+        if count == 1 && *cursor >= text_end {
+            start = text_end;
+        } else {
+            end = text_end;
+        }
+    } else if end == last {
+        // Nothing after the last sentence, take the blanks before it instead
+        while let Some(previous) = graphemes
+            .previous(&start)
+            .filter(|previous| *previous >= first && graphemes.is_blank(previous))
+        {
+            start = previous;
+        }
+    }
+
+    charwise(start, end)
+}
+
+// Quotes are matched on the cursor row, the first quoted text after the cursor when it isn't in one
+fn quote(row: &Row, at: &Position, quote: char, inner: bool) -> Option<Selection> {
+    let quote = quote.to_string();
+    let cursor = at.x;
+    let is_blank = |x: usize| {
+        row.grapheme(x)
+            .is_some_and(|grapheme| grapheme.trim().is_empty())
+    };
+
+    // Quotes escaped with a backslash don't count
+    let quotes: Vec<usize> = (0..row.len())
+        .filter(|&x| {
+            row.grapheme(x) == Some(quote.as_str()) && (x == 0 || row.grapheme(x - 1) != Some("\\"))
+        })
+        .collect();
+
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|(open, close)| *open <= cursor && cursor <= *close)
+        .or_else(|| {
+            quotes
+                .chunks_exact(2)
+                .map(|pair| (pair[0], pair[1]))
+                .find(|(open, _)| *open > cursor)
+        })?;
+
+    let (mut start, mut end) = if inner {
+        (open + 1, close)
+    } else {
+        (open, close + 1)
+    };
+
+    // Blanks after the closing quote, or before the opening one
+    if !inner {
+        while end < row.len() && is_blank(end) {
+            end += 1;
+        }
+        if end == close + 1 {
+            while start > 0 && is_blank(start - 1) {
+                start -= 1;
+            }
+        }
+    }
+    charwise(Position::new(start, at.y), Position::new(end, at.y))
+}
+
+// Brackets can span rows, a count selects the brackets enclosing the ones around the cursor
+fn bracket(
+    graphemes: &Graphemes,
+    cursor: &Position,
+    open: char,
+    close: char,
+    inner: bool,
+    count: usize,
+) -> Option<Selection> {
+    let open = open.to_string();
+    let close = close.to_string();
+
+    // Unmatched opening bracket before the cursor, the cursor can be on either bracket
+    let mut start = cursor.clone();
+    if graphemes.is(&start, close.as_str()) {
+        start = graphemes.previous(&start)?;
+    }
+    let mut depth = 0;
+    let mut remaining = count;
+    loop {
+        match graphemes.get(&start) {
+            Some(grapheme) if grapheme == open && depth == 0 => {
+                remaining -= 1;
+                if remaining == 0 {
+                    break;
+                }
+            }
+            Some(grapheme) if grapheme == open => depth -= 1,
+            Some(grapheme) if grapheme == close => depth += 1,
+            _ => {}
+        }
+        start = graphemes.previous(&start)?;
+    }
+
+    let after_open = graphemes.next(&start)?;
+    let mut end = after_open.clone();
+    let mut depth = 0;
+    loop {
+        match graphemes.get(&end)? {
+            grapheme if grapheme == close && depth == 0 => break,
+            grapheme if grapheme == close => depth -= 1,
+            grapheme if grapheme == open => depth += 1,
+            _ => {}
+        }
+        end = graphemes.next(&end)?;
+    }
+
+    if !inner {
+        return charwise(start, graphemes.next(&end)?);
+    }
+
+    // Brackets on rows of their own leave those rows alone, the rows between them are selected
+    let mut before_close = end.clone();
+    while let Some(previous) = graphemes
+        .previous(&before_close)
+        .filter(|previous| *previous > start && graphemes.is_blank(previous))
+    {
+        if graphemes.is(&previous, LINE_BREAK) {
+            break;
+        }
+        before_close = previous;
+    }
+    let line_break_before_close = graphemes
+        .previous(&before_close)
+        .filter(|previous| *previous > after_open && graphemes.is(previous, LINE_BREAK));
+    if graphemes.is(&after_open, LINE_BREAK) && line_break_before_close.is_some() {
+        return Some(Selection {
+            start: Position::new(0, start.y + 1),
+            end: Position::new(0, end.y - 1),
+            kind: SelectionKind::Linewise,
+        });
+    }
+
+    charwise(after_open, end)
+}
+
+// A tag of an XML or HTML document, from its < up to after its >
+struct Tag {
+    name: String,
+    closing: bool,
+    start: Position,
+    end: Position,
+}
+
+impl Tag {
+    // The tag starting at a <, comments, declarations and self-closing tags aren't tags
+    fn at(graphemes: &Graphemes, start: &Position) -> Option<Self> {
+        if !graphemes.is(start, "<") {
+            return None;
+        }
+
+        let mut text = String::new();
+        let mut end = graphemes.next(start)?;
+        loop {
+            match graphemes.get(&end)?.as_str() {
+                ">" => break,
+                "<" => return None,
+                grapheme => text.push_str(grapheme),
+            }
+            end = graphemes.next(&end)?;
+        }
+
+        let closing = text.starts_with('/');
+        let name: String = text
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| !c.is_whitespace() && *c != '/')
+            .collect();
+        if name.is_empty() || text.starts_with(['!', '?']) || text.ends_with('/') {
+            return None;
+        }
+
+        Some(Self {
+            name,
+            closing,
+            start: start.clone(),
+            end: graphemes.next(&end)?,
+        })
+    }
+
+    // HTML elements that are never closed, like <br>
+    fn is_void(&self) -> bool {
+        VOID_ELEMENTS
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&self.name))
+    }
+}
+
+// it and at select the tags enclosing the cursor, from the innermost one. The tags are read
+// from the start of the document with the ones left open on a stack, a closing tag closes
+// the last one of its name along with the ones opened after it, which were never closed.
+fn tag(graphemes: &Graphemes, cursor: &Position, inner: bool, count: usize) -> Option<Selection> {
+    let mut open: Vec<Tag> = Vec::new();
+    let mut at = Position::default();
+    let mut remaining = count;
+
+    loop {
+        // Nothing opened before the cursor is left to enclose it
+        if at > *cursor && open.first().is_none_or(|opening| opening.start > *cursor) {
+            return None;
+        }
+
+        let Some(tag) = Tag::at(graphemes, &at) else {
+            at = graphemes.next(&at)?;
+            continue;
+        };
+        at = tag.end.clone();
+
+        if !tag.closing {
+            if !tag.is_void() {
+                open.push(tag);
+            }
+            continue;
+        }
+        let Some(index) = open.iter().rposition(|opening| opening.name == tag.name) else {
+            continue;
+        };
+        let opening = open.drain(index..).next()?;
+
+        if opening.start <= *cursor && *cursor < tag.end {
+            remaining -= 1;
+            if remaining == 0 {
+                return if inner {
+                    charwise(opening.end, tag.start)
+                } else {
+                    charwise(opening.start, tag.end)
+                };
+            }
+        }
+    }
+}
+
+// ip is the block of blank or non-blank rows around the cursor, ap adds the blank rows after it
+fn paragraph(document: &Document, y: usize, inner: bool, count: usize) -> Option<Selection> {
+    let len = document.len();
+    if len == 0 {
+        return None;
+    }
+
+    let is_blank = |y: usize| {
+        document
+            .row(y)
+            .is_none_or(|row| row.as_str().trim().is_empty())
+    };
+    let block_end = |y: usize| {
+        let blank = is_blank(y);
+        let mut end = y;
+        while end + 1 < len && is_blank(end + 1) == blank {
+            end += 1;
+        }
+        end
+    };
+
+    let y = cmp::min(y, len - 1);
+    let blank = is_blank(y);
+    let mut start = y;
+    while start > 0 && is_blank(start - 1) == blank {
+        start -= 1;
+    }
+
+    let mut end = block_end(y);
+    let blocks = if inner { count } else { count * 2 };
+    for _ in 1..blocks {
+        if end + 1 >= len {
+            break;
+        }
+        end = block_end(end + 1);
+    }
+
+    // Without blank rows after the paragraph, ap takes the ones before it
+    if !inner && !blank && !is_blank(end) {
+        while start > 0 && is_blank(start - 1) {
+            start -= 1;
+        }
+    }
+
+    Some(Selection {
+        start: Position::new(0, start),
+        end: Position::new(0, end),
+        kind: SelectionKind::Linewise,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Text selected by the object with the cursor at x, y, linewise selections as whole rows
+    fn selected(
+        text: &str,
+        (x, y): (usize, usize),
+        object: TextObject,
+        inner: bool,
+        count: usize,
+    ) -> Option<String> {
+        let mut document = Document::default();
+        document.insert_str(&Position::new(0, 0), text);
+
+        let selection = selection(&document, &Position::new(x, y), object, inner, count)?;
+        Some(match selection.kind {
+            SelectionKind::Linewise => (selection.start.y..=selection.end.y)
+                .filter_map(|y| document.row(y))
+                .map(|row| format!("{}\n", row.as_str()))
+                .collect(),
+            _ => document.text_range(&selection.start, &selection.end),
+        })
+    }
+
+    #[test]
+    fn word() {
+        let text = "foo  bar.baz qux";
+        let word = |x, inner, count| selected(text, (x, 0), TextObject::Word, inner, count);
+
+        assert_eq!(word(6, true, 1).as_deref(), Some("bar"));
+        // No blanks after it, the ones before it
+        assert_eq!(word(6, false, 1).as_deref(), Some("  bar"));
+        assert_eq!(word(0, false, 1).as_deref(), Some("foo  "));
+        assert_eq!(word(3, true, 1).as_deref(), Some("  "));
+        assert_eq!(word(0, true, 3).as_deref(), Some("foo  bar"));
+        assert_eq!(word(14, false, 1).as_deref(), Some(" qux"));
+        assert_eq!(
+            selected(text, (6, 0), TextObject::BigWord, true, 1).as_deref(),
+            Some("bar.baz")
+        );
+    }
+
+    #[test]
+    fn sentence() {
+        let text = "One two. Three (four)!  Five?\n\nSix.";
+        let sentence = |x, y, inner| selected(text, (x, y), TextObject::Sentence, inner, 1);
+
+        assert_eq!(sentence(1, 0, true).as_deref(), Some("One two."));
+        assert_eq!(sentence(1, 0, false).as_deref(), Some("One two. "));
+        assert_eq!(sentence(10, 0, true).as_deref(), Some("Three (four)!"));
+        assert_eq!(sentence(26, 0, false).as_deref(), Some("  Five?"));
+        assert_eq!(sentence(0, 2, true).as_deref(), Some("Six."));
+        assert_eq!(sentence(0, 1, true), None);
+        assert_eq!(
+            selected(text, (1, 0), TextObject::Sentence, false, 2).as_deref(),
+            Some("One two. Three (four)!  ")
+        );
+    }
+
+    #[test]
+    fn quote() {
+        let text = r#"say "hello world" and "a\"b" end"#;
+        let quote = |x, inner| selected(text, (x, 0), TextObject::Quote('"'), inner, 1);
+
+        assert_eq!(quote(6, true).as_deref(), Some("hello world"));
+        assert_eq!(quote(6, false).as_deref(), Some("\"hello world\" "));
+        assert_eq!(quote(4, true).as_deref(), Some("hello world"));
+        // Before any quote, the first quoted text after the cursor
+        assert_eq!(quote(0, true).as_deref(), Some("hello world"));
+        assert_eq!(quote(24, true).as_deref(), Some("a\\\"b"));
+        assert_eq!(quote(30, true), None);
+    }
+
+    #[test]
+    fn bracket() {
+        let text = "f(a, (b), c)\nif (x) {\n    y;\n}";
+        let bracket = |x, y, inner, count| {
+            selected(text, (x, y), TextObject::Bracket('(', ')'), inner, count)
+        };
+
+        assert_eq!(bracket(6, 0, true, 1).as_deref(), Some("b"));
+        assert_eq!(bracket(6, 0, false, 1).as_deref(), Some("(b)"));
+        assert_eq!(bracket(6, 0, true, 2).as_deref(), Some("a, (b), c"));
+        assert_eq!(bracket(1, 0, false, 1).as_deref(), Some("(a, (b), c)"));
+        assert_eq!(bracket(0, 0, true, 1), None);
+        // Brackets on rows of their own leave those rows out
+        assert_eq!(
+            selected(text, (4, 2), TextObject::Bracket('{', '}'), true, 1).as_deref(),
+            Some("    y;\n")
+        );
+    }
+
+    #[test]
+    fn tag() {
+        let text = "<div a=\"1\"><p>hi <b>bo</b> <br> x</p>\n<!-- c --><img/> <p>two</p></div>";
+        let tag = |x, y, inner, count| selected(text, (x, y), TextObject::Tag, inner, count);
+
+        assert_eq!(tag(21, 0, true, 1).as_deref(), Some("bo"));
+        assert_eq!(tag(21, 0, false, 1).as_deref(), Some("<b>bo</b>"));
+        assert_eq!(tag(14, 0, true, 1).as_deref(), Some("hi <b>bo</b> <br> x"));
+        // <br> is never closed, the tag around it is selected
+        assert_eq!(tag(30, 0, true, 1).as_deref(), Some("hi <b>bo</b> <br> x"));
+        assert_eq!(
+            tag(21, 0, true, 3).as_deref(),
+            Some(&text[11..text.len() - 6])
+        );
+        assert_eq!(tag(20, 1, true, 1).as_deref(), Some("two"));
+        assert_eq!(tag(21, 0, true, 4), None);
+    }
+
+    #[test]
+    fn unclosed_tags_are_skipped() {
+        let text = "<ul><li>one<li>two</ul>";
+
+        assert_eq!(
+            selected(text, (16, 0), TextObject::Tag, true, 1).as_deref(),
+            Some("<li>one<li>two")
+        );
+        assert_eq!(selected("<p>x", (3, 0), TextObject::Tag, true, 1), None);
+    }
+
+    #[test]
+    fn paragraph() {
+        let text = "one\ntwo\n\n\nthree\n\nfour";
+        let paragraph =
+            |y, inner, count| selected(text, (0, y), TextObject::Paragraph, inner, count);
+
+        assert_eq!(paragraph(1, true, 1).as_deref(), Some("one\ntwo\n"));
+        assert_eq!(paragraph(0, false, 1).as_deref(), Some("one\ntwo\n\n\n"));
+        assert_eq!(paragraph(2, true, 1).as_deref(), Some("\n\n"));
+        assert_eq!(
+            paragraph(0, true, 3).as_deref(),
+            Some("one\ntwo\n\n\nthree\n")
+        );
+        // Without blank rows after it, the ones before it
+        assert_eq!(paragraph(6, false, 1).as_deref(), Some("\nfour\n"));
+    }
+}
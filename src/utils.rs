@@ -1 +1,181 @@
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::thread;
+use unicode_segmentation::UnicodeSegmentation;
 
+const MAX_SYMLINK_DEPTH: usize = 32;
+
+// Writes a file through a temporary file in the same directory that is then renamed over it,
+// so a crash or a full disk in the middle of a save never leaves a truncated file behind
+pub fn write_atomically<F>(path: &str, backup: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    // Write through symlinks instead of replacing them
+    let target = resolve_symlinks(Path::new(path))?;
+    let permissions = fs::metadata(&target).ok().map(|meta| meta.permissions());
+
+    // The backup is written the same way, keeping the permissions of the file
+    if backup && target.exists() {
+        let mut backup_path = target.clone().into_os_string();
+        backup_path.push("~");
+        let backup_path = resolve_symlinks(Path::new(&backup_path))?;
+        replace(&backup_path, permissions.clone(), |file| {
+            io::copy(&mut fs::File::open(&target)?, file).map(|_| ())
+        })?;
+    }
+
+    replace(&target, permissions, write)
+}
+
+// Writes to a temporary file next to the target and renames it over the target
+fn replace<F>(target: &Path, permissions: Option<Permissions>, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let directory = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp_path = directory.join(format!(".{}.{}.tmp", file_name, process::id()));
+
+    let result = (|| {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        let mut writer = BufWriter::new(file);
+
+        write(&mut writer)?;
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&temp_path, permissions)?;
+        }
+
+        fs::rename(&temp_path, target)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+
+    // Make sure the rename itself survives a crash, not every platform can open a directory
+    if let Ok(directory) = fs::File::open(&directory) {
+        let _ = directory.sync_all();
+    }
+
+    Ok(())
+}
+
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let link = fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(parent) => parent.join(link),
+                    None => link,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+
+    Err(io::Error::other("Too many levels of symbolic links"))
+}
+
+// Runs a shell command with text on its standard input, returns what it printed and how it exited
+pub fn pipe_to_command(command: &str, input: String) -> io::Result<(String, ExitStatus)> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write from another thread, the command may fill its output before reading everything
+    let stdin = child.stdin.take();
+    let writer = thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    let mut printed = String::from_utf8_lossy(&output.stdout).to_string();
+    printed.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((printed, output.status))
+}
+
+// Cuts or pads the text with spaces so it is exactly `width` graphemes long
+pub fn pad(text: &str, width: usize) -> String {
+    let mut result: String = text.graphemes(true).take(width).collect();
+    let len = result.graphemes(true).count();
+
+    result.push_str(&" ".repeat(width.saturating_sub(len)));
+    result
+}
+
+pub fn toggle_case(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_lowercase() {
+                c.to_uppercase().to_string()
+            } else {
+                c.to_lowercase().to_string()
+            }
+        })
+        .collect()
+}
+
+// Control characters would be interpreted by the terminal, show their symbol instead (␛ for Esc)
+pub fn show_control_chars(text: &str) -> String {
+    text.chars()
+        .map(|c| match c as u32 {
+            0x09 => c,
+            0..=0x1f => char::from_u32(0x2400 + c as u32).unwrap_or(c),
+            0x7f => '\u{2421}',
+            _ => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn backup_keeps_the_old_contents_and_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("miv-{}-backup", process::id()));
+        let backup_path = path.with_file_name(format!("miv-{}-backup~", process::id()));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+
+        write_atomically(&path.to_string_lossy(), true, |file| file.write_all(b"new")).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&backup_path).unwrap(), "old");
+        let mode = fs::metadata(&backup_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup_path).unwrap();
+    }
+}
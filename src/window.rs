@@ -0,0 +1,9 @@
+use crate::Position;
+
+// A view on a buffer, with its own cursor and scroll offset
+pub struct Window {
+    pub id: usize,
+    pub buffer_id: usize,
+    pub cursor_position: Position,
+    pub offset: Position,
+}